@@ -1,44 +1,155 @@
+use crate::certificate::{load_client_identity, load_root_certificates, ClientCertificate};
+use crate::settings::{ClientCredential, ExportMode, OAuthVersion, TenantSettings};
 use crate::AppSettings;
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
-use chrono::{DateTime, Utc};
-use oauth2::basic::{BasicClient as Oauth2BasicClient, BasicTokenResponse};
-use oauth2::reqwest::async_http_client;
-use oauth2::{AuthUrl, Scope, TokenResponse, TokenUrl};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use oauth2::basic::{
+    BasicClient as Oauth2BasicClient, BasicErrorResponse, BasicErrorResponseType,
+    BasicTokenResponse, BasicTokenType,
+};
+use oauth2::reqwest::Error as Oauth2HttpError;
+use oauth2::{
+    AccessToken, AuthUrl, ClientId, EmptyExtraTokenFields, HttpRequest, HttpResponse,
+    RequestTokenError, Scope, TokenResponse, TokenUrl,
+};
 use prometheus_client::encoding::text::Encode;
+use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
 use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
 use prometheus_client::registry::{Registry, Unit};
+use rand::Rng;
+use regex::Regex;
 
-use crate::exporter::PromScraper;
-use reqwest::Client as HttpClient;
-use serde::Deserialize;
+use crate::exporter::{new_registry, PromScraper, ScrapeError};
+use futures_util::future::try_join_all;
+use futures_util::TryStreamExt;
+use reqwest::header::RETRY_AFTER;
+use reqwest::redirect::Policy as RedirectPolicy;
+use reqwest::{Client as HttpClient, ClientBuilder, NoProxy, Proxy, Response, StatusCode, Url};
+use serde::de::{
+    DeserializeSeed, Deserializer, Error as _, IgnoredAny, MapAccess, SeqAccess, Visitor,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashSet;
 use std::fmt::{Display, Formatter};
-use std::sync::atomic::AtomicU64;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::future::Future;
+use std::io::{self, BufReader, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{mpsc, watch, RwLock, Semaphore};
 use tokio::time::{Duration, Instant};
-use tracing::warn;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use tracing::field::Empty;
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument, Span};
+use uuid::Uuid;
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
-static AZURE_BASE_URL: &str = "https://login.microsoftonline.com";
 static AZURE_AUTH_PATH: &str = "oauth2/v2.0/authorize";
 static AZURE_TOKEN_PATH: &str = "oauth2/v2.0/token";
-static AZURE_SCOPE: &str = "https://graph.microsoft.com/.default";
-static AZURE_APPLICATIONS_ENDPOINT: &str = "https://graph.microsoft.com/v1.0/applications/";
-static AZURE_TOKEN_MIN_LIFETIME: u64 = 60;
-static AZURE_TOKEN_FETCH_RETRY: u64 = 10;
+static AZURE_V1_AUTH_PATH: &str = "oauth2/authorize";
+static AZURE_V1_TOKEN_PATH: &str = "oauth2/token";
+static AZURE_SCOPE_PATH: &str = ".default";
+static AZURE_APPLICATIONS_PATH: &str = "v1.0/applications/";
+static AZURE_SERVICE_PRINCIPALS_PATH: &str = "v1.0/servicePrincipals/";
+static AZURE_DELETED_APPLICATIONS_PATH: &str =
+    "v1.0/directory/deletedItems/microsoft.graph.application";
+static AZURE_TOKEN_RETRY_MIN: u64 = 1;
+static AZURE_TOKEN_RETRY_MAX: u64 = 60;
+/// The largest share of the time until the refresh by which it's randomly brought forward.
+static AZURE_TOKEN_REFRESH_JITTER: f64 = 0.05;
+static GRAPH_MAX_RETRIES: u32 = 3;
+static GRAPH_DEFAULT_RETRY_AFTER: u64 = 5;
+static GRAPH_MAX_RETRY_AFTER: u64 = 60;
+static GRAPH_CLIENT_REQUEST_ID_HEADER: &str = "client-request-id";
+static GRAPH_REQUEST_ID_HEADER: &str = "request-id";
+/// The delay before retrying a request which failed on the network, doubled for each attempt.
+static GRAPH_RETRY_BACKOFF: u64 = 2;
+/// How many parsed apps may wait for the recorder before parsing pauses.
+static GRAPH_APPS_BUFFER: usize = 64;
+static NEVER_EXPIRES_HORIZON_YEARS: i64 = 100;
+/// How far the clock may drift from Azure's before it's worth a warning, accounting for the time the response took.
+static CLOCK_SKEW_WARNING_SECONDS: i64 = 120;
+static PERMISSION_CHECK_TOKEN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+static IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+static IMDS_API_VERSION: &str = "2018-02-01";
+static APP_SERVICE_IDENTITY_ENDPOINT_VAR: &str = "IDENTITY_ENDPOINT";
+static APP_SERVICE_IDENTITY_HEADER_VAR: &str = "IDENTITY_HEADER";
+static APP_SERVICE_IDENTITY_API_VERSION: &str = "2019-08-01";
+static CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// The Azure cloud the tenant lives in, which determines the login and Graph endpoints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AzureCloud {
+    Public,
+    UsGovernment,
+    China,
+    Germany,
+}
+
+impl AzureCloud {
+    fn login_url(&self) -> &'static str {
+        match self {
+            Self::Public => "https://login.microsoftonline.com",
+            Self::UsGovernment => "https://login.microsoftonline.us",
+            Self::China => "https://login.chinacloudapi.cn",
+            Self::Germany => "https://login.microsoftonline.de",
+        }
+    }
+
+    /// The name the cloud is configured with.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Public => "public",
+            Self::UsGovernment => "usgov",
+            Self::China => "china",
+            Self::Germany => "germany",
+        }
+    }
+
+    fn graph_url(&self) -> &'static str {
+        match self {
+            Self::Public => "https://graph.microsoft.com",
+            Self::UsGovernment => "https://graph.microsoft.us",
+            Self::China => "https://microsoftgraph.chinacloudapi.cn",
+            Self::Germany => "https://graph.microsoft.de",
+        }
+    }
+}
+
+impl FromStr for AzureCloud {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "public" => Ok(Self::Public),
+            "usgov" => Ok(Self::UsGovernment),
+            "china" => Ok(Self::China),
+            "germany" => Ok(Self::Germany),
+            _ => Err(anyhow!("Unknown Azure cloud: {}", s)),
+        }
+    }
+}
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 struct Credentials {
-    // custom_key_identifier: Option<String>,
+    /// Base64-encoded, usually the SHA-1 thumbprint of a certificate.
+    custom_key_identifier: Option<String>,
     display_name: Option<String>,
-    end_date_time: DateTime<Utc>,
-    // hint: Option<String>,
+    end_date_time: Option<DateTime<Utc>>,
+    /// The first characters of a client secret, absent for certificates.
+    #[serde(default)]
+    hint: Option<String>,
     key_id: String,
-    // start_date_time: DateTime<Utc>,
+    /// Sometimes missing or null, which mustn't fail the whole page.
+    start_date_time: Option<DateTime<Utc>>,
 }
 
 impl Display for Credentials {
@@ -47,7 +158,30 @@ impl Display for Credentials {
             .display_name
             .as_ref()
             .map_or_else(String::new, |v| format!(" ({})", v));
-        write!(f, "{}{}: {}", self.key_id, display_name, self.end_date_time)
+        match self.end_date_time {
+            Some(end_date_time) => write!(f, "{}{}: {}", self.key_id, display_name, end_date_time),
+            None => write!(f, "{}{}: never", self.key_id, display_name),
+        }
+    }
+}
+
+impl Credentials {
+    /// The expiration date, unless the credential doesn't have one or it is so far away that it never expires in practice,
+    /// such as the year 9999 set by some tools.
+    fn expiration(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.end_date_time
+            .filter(|end| *end - now < ChronoDuration::days(365 * NEVER_EXPIRES_HORIZON_YEARS))
+    }
+
+    /// The custom key identifier as uppercase hex, as certificate thumbprints are usually shown.
+    fn thumbprint(&self) -> Option<String> {
+        let identifier = base64::decode(self.custom_key_identifier.as_ref()?).ok()?;
+        Some(
+            identifier
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect(),
+        )
     }
 }
 
@@ -56,6 +190,12 @@ impl Display for Credentials {
 struct AzureApp {
     app_id: String,
     display_name: String,
+    /// Only selected when tags are exported.
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Who can sign in to the app, such as `AzureADMultipleOrgs`, only selected when exported.
+    #[serde(default)]
+    sign_in_audience: Option<String>,
     password_credentials: Vec<Credentials>,
     key_credentials: Vec<Credentials>,
 }
@@ -88,90 +228,746 @@ impl Display for AzureApp {
     }
 }
 
-#[derive(Deserialize, Debug)]
+/// What a tenant's scrape recorded so far.
+#[derive(Default)]
+struct ScrapeProgress {
+    /// How many pages were entirely fetched.
+    pages: u64,
+    recorded: u64,
+    earliest_expiration: Option<DateTime<Utc>>,
+}
+
+/// Deserializes a page of objects, handing each app over as soon as it's parsed instead of collecting the whole page.
+///
+/// Yields the link to the next page, if any.
 struct ResponsePage {
-    #[serde(rename = "@odata.nextLink")]
-    next_link: Option<String>,
-    value: Vec<AzureApp>,
+    sender: mpsc::Sender<AzureApp>,
+}
+
+impl<'de> DeserializeSeed<'de> for ResponsePage {
+    type Value = Option<String>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ResponsePage {
+    type Value = Option<String>;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("a page of Graph objects")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut next_link = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "@odata.nextLink" => next_link = map.next_value()?,
+                "value" => map.next_value_seed(ResponseApps {
+                    sender: &self.sender,
+                })?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(next_link)
+    }
+}
+
+/// The `value` array of a page, sending each app to the recorder.
+struct ResponseApps<'a> {
+    sender: &'a mpsc::Sender<AzureApp>,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for ResponseApps<'a> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for ResponseApps<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("a list of apps")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        while let Some(app) = seq.next_element::<AzureApp>()? {
+            self.sender
+                .blocking_send(app)
+                .map_err(|_| A::Error::custom("the app recorder has stopped"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a page as its body comes in, so that only a handful of apps are held in memory at once.
+///
+/// The JSON parser is synchronous, so it runs on the blocking pool, reading from the body stream.
+async fn stream_page(response: Response, sender: mpsc::Sender<AzureApp>) -> Result<Option<String>> {
+    let body = StreamReader::new(
+        response
+            .bytes_stream()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+    );
+    let reader = BufReader::new(SyncIoBridge::new(body));
+    tokio::task::spawn_blocking(move || {
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+        let next_link = ResponsePage { sender }.deserialize(&mut deserializer)?;
+        deserializer.end()?;
+        Ok(next_link)
+    })
+    .await?
 }
 
 struct Token {
     token_response: BasicTokenResponse,
+    /// When the token should be refreshed, ahead of its actual expiration.
     expires_at: Instant,
+    /// When Azure considers the token expired.
+    expires_on: DateTime<Utc>,
 }
 
-pub struct AzureClientTokenProvider {
+type TokenRequestError = RequestTokenError<Oauth2HttpError<reqwest::Error>, BasicErrorResponse>;
+
+/// Why a token refresh failed.
+#[derive(Clone, Hash, PartialEq, Eq)]
+enum RefreshFailure {
+    /// The token endpoint couldn't be reached.
+    Network,
+    /// The token endpoint refused to issue a token, for example because of invalid credentials.
+    Rejected,
+    /// The token endpoint's response couldn't be parsed.
+    InvalidResponse,
+    /// The token was issued without an expiration.
+    MissingExpiry,
+    Other,
+}
+
+impl RefreshFailure {
+    fn from_error(err: &anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+            // Managed identity requests are made directly with reqwest.
+            return if err.is_status() {
+                Self::Rejected
+            } else if err.is_decode() {
+                Self::InvalidResponse
+            } else {
+                Self::Network
+            };
+        }
+        match err.downcast_ref::<TokenRequestError>() {
+            Some(RequestTokenError::Request(_)) => Self::Network,
+            Some(RequestTokenError::ServerResponse(_)) => Self::Rejected,
+            Some(RequestTokenError::Parse(_, _)) => Self::InvalidResponse,
+            Some(RequestTokenError::Other(_)) | None => Self::Other,
+        }
+    }
+}
+
+/// Whether the token endpoint refused the request in a way retrying won't fix, such as `invalid_client` for a wrong
+/// secret, as opposed to transient failures such as network errors or throttling.
+fn is_fatal_refresh_error(err: &anyhow::Error) -> bool {
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        // Managed identity requests are made directly with reqwest.
+        return err.status().is_some_and(|status| {
+            status.is_client_error()
+                && status != StatusCode::REQUEST_TIMEOUT
+                && status != StatusCode::TOO_MANY_REQUESTS
+        });
+    }
+    match err.downcast_ref::<TokenRequestError>() {
+        Some(RequestTokenError::ServerResponse(response)) => {
+            !matches!(response.error(), BasicErrorResponseType::Extension(_))
+        }
+        _ => false,
+    }
+}
+
+/// The count of consecutive fatal refresh failures once `err` happened, transient failures breaking the streak.
+fn consecutive_fatal_failures(previous: u32, err: &anyhow::Error) -> u32 {
+    if is_fatal_refresh_error(err) {
+        previous + 1
+    } else {
+        0
+    }
+}
+
+impl Encode for RefreshFailure {
+    fn encode(&self, writer: &mut dyn Write) -> std::result::Result<(), std::io::Error> {
+        let str = match self {
+            Self::Network => "network",
+            Self::Rejected => "rejected",
+            Self::InvalidResponse => "invalid_response",
+            Self::MissingExpiry => "missing_expiry",
+            Self::Other => "other",
+        };
+        write!(writer, "{}", str)
+    }
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Encode)]
+struct TenantLabels {
+    tenant_id: String,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Encode)]
+struct TenantInfoLabels {
+    tenant_id: String,
+    cloud: &'static str,
+    scope: String,
+    /// The scraped object types, comma-separated.
+    object_types: String,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Encode)]
+struct RefreshFailureLabels {
+    tenant_id: String,
+    reason: RefreshFailure,
+}
+
+/// Metrics describing the state of the Azure tokens, shared by the providers of all the tenants.
+struct TokenMetrics {
+    expires_at: Family<TenantLabels, Gauge>,
+    last_refresh: Family<TenantLabels, Gauge>,
+    next_refresh: Family<TenantLabels, Gauge>,
+    refresh_successes: Family<TenantLabels, Counter>,
+    refresh_failures: Family<RefreshFailureLabels, Counter>,
+    acquisition_duration: Family<TenantLabels, Histogram, fn() -> Histogram>,
+}
+
+impl Default for TokenMetrics {
+    fn default() -> Self {
+        Self {
+            expires_at: Family::default(),
+            last_refresh: Family::default(),
+            next_refresh: Family::default(),
+            refresh_successes: Family::default(),
+            refresh_failures: Family::default(),
+            // From 50ms to about 25s.
+            acquisition_duration: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.05, 2.0, 10))
+            }),
+        }
+    }
+}
+
+impl TokenMetrics {
+    fn register(&self, registry: &mut Registry) {
+        registry.register_with_unit(
+            "azure_token_expires_at",
+            "Timestamp of the current Azure token expiration, 0 if there is none",
+            Unit::Seconds,
+            Box::new(self.expires_at.clone()),
+        );
+        registry.register_with_unit(
+            "azure_token_last_refresh",
+            "Timestamp of the last successful Azure token refresh",
+            Unit::Seconds,
+            Box::new(self.last_refresh.clone()),
+        );
+        registry.register_with_unit(
+            "azure_token_next_refresh",
+            "Timestamp of the next scheduled Azure token refresh, or retry after a failure",
+            Unit::Seconds,
+            Box::new(self.next_refresh.clone()),
+        );
+        registry.register(
+            "azure_token_refresh_successes",
+            "Number of successful Azure token refreshes",
+            Box::new(self.refresh_successes.clone()),
+        );
+        registry.register(
+            "azure_token_refresh_failures",
+            "Number of failed Azure token refreshes, by reason",
+            Box::new(self.refresh_failures.clone()),
+        );
+        registry.register_with_unit(
+            "azure_token_acquisition_duration",
+            "Time taken by the token endpoint to issue a token or fail",
+            Unit::Seconds,
+            Box::new(self.acquisition_duration.clone()),
+        );
+    }
+
+    fn record_failure(&self, tenant: &TenantLabels, reason: RefreshFailure) {
+        self.refresh_failures
+            .get_or_create(&RefreshFailureLabels {
+                tenant_id: tenant.tenant_id.clone(),
+                reason,
+            })
+            .inc();
+    }
+}
+
+/// Where the client assertions sent instead of a client secret come from.
+enum ClientAssertion {
+    /// Signed with the app's certificate.
+    Certificate(ClientCertificate),
+    /// Issued by a trusted OIDC provider. The file is read for every request, as the provider rotates the token.
+    FederatedToken(PathBuf),
+}
+
+impl ClientAssertion {
+    fn get(&self, client_id: &str, audience: &str) -> Result<String> {
+        match self {
+            Self::Certificate(certificate) => certificate.assertion(client_id, audience),
+            Self::FederatedToken(path) => {
+                let token = std::fs::read_to_string(path).with_context(|| {
+                    format!("Failed to read federated token {}", path.display())
+                })?;
+                Ok(token.trim().to_string())
+            }
+        }
+    }
+}
+
+/// How the tokens are obtained.
+enum TokenSource {
+    ClientCredentials(Box<ClientCredentials>),
+    ManagedIdentity(ManagedIdentity),
+}
+
+/// The OAuth2 client credentials flow against the tenant's token endpoint.
+struct ClientCredentials {
     oauth2_client: Oauth2BasicClient,
+    client_id: ClientId,
+    token_url: TokenUrl,
+    client_assertion: Option<ClientAssertion>,
+    scope: String,
+    oauth_version: OAuthVersion,
+}
+
+impl ClientCredentials {
+    async fn request_token(&self, http_client: &HttpClient) -> Result<BasicTokenResponse> {
+        let request = self.oauth2_client.exchange_client_credentials();
+        let mut request = match self.oauth_version {
+            OAuthVersion::V2 => request.add_scope(Scope::new(self.scope.clone())),
+            // The v1 endpoint takes the resource instead of a scope.
+            OAuthVersion::V1 => request.add_extra_param("resource", scope_resource(&self.scope)),
+        };
+        if let Some(client_assertion) = &self.client_assertion {
+            let assertion = client_assertion.get(&self.client_id, &self.token_url)?;
+            request = request
+                .add_extra_param("client_assertion_type", CLIENT_ASSERTION_TYPE)
+                .add_extra_param("client_assertion", assertion);
+        }
+
+        request
+            .request_async(|request| send_token_request(http_client, request))
+            .await
+            .context("Failed to retrieve Azure token")
+    }
+}
+
+/// Requests tokens from the managed identity endpoint of the Azure VM or App Service the exporter runs on, which doesn't
+/// need any credential.
+struct ManagedIdentity {
+    endpoint: String,
+    api_version: &'static str,
+    /// The secret header App Service requires, absent on VMs.
+    identity_header: Option<String>,
+    /// The client id of a user-assigned identity, or none for the system-assigned one.
+    client_id: Option<String>,
+    resource: String,
+}
+
+/// The token returned by the managed identity endpoint, whose numbers are strings.
+#[derive(Deserialize)]
+struct ManagedIdentityToken {
+    access_token: String,
+    expires_in: Option<JsonValue>,
+    expires_on: Option<JsonValue>,
+}
+
+impl ManagedIdentity {
+    fn new(client_id: Option<String>, resource: String) -> Self {
+        // App Service exposes its own endpoint, while VMs use the Instance Metadata Service.
+        match (
+            std::env::var(APP_SERVICE_IDENTITY_ENDPOINT_VAR),
+            std::env::var(APP_SERVICE_IDENTITY_HEADER_VAR),
+        ) {
+            (Ok(endpoint), Ok(identity_header)) => Self {
+                endpoint,
+                api_version: APP_SERVICE_IDENTITY_API_VERSION,
+                identity_header: Some(identity_header),
+                client_id,
+                resource,
+            },
+            _ => Self {
+                endpoint: IMDS_TOKEN_URL.to_string(),
+                api_version: IMDS_API_VERSION,
+                identity_header: None,
+                client_id,
+                resource,
+            },
+        }
+    }
+
+    async fn request_token(&self, http_client: &HttpClient) -> Result<BasicTokenResponse> {
+        let mut query = vec![
+            ("api-version", self.api_version),
+            ("resource", self.resource.as_str()),
+        ];
+        if let Some(client_id) = &self.client_id {
+            query.push(("client_id", client_id));
+        }
+        let mut request = http_client
+            .get(&self.endpoint)
+            .query(&query)
+            .header("Metadata", "true");
+        if let Some(identity_header) = &self.identity_header {
+            request = request.header("X-IDENTITY-HEADER", identity_header);
+        }
+
+        let token = request
+            .send()
+            .await
+            .and_then(Response::error_for_status)
+            .context("Failed to retrieve Azure token from the managed identity endpoint")?
+            .json::<ManagedIdentityToken>()
+            .await
+            .context("Invalid token from the managed identity endpoint")?;
+
+        let mut token_response = BasicTokenResponse::new(
+            AccessToken::new(token.access_token),
+            BasicTokenType::Bearer,
+            EmptyExtraTokenFields {},
+        );
+        // App Service only gives the expiration timestamp.
+        let expires_in = json_u64(token.expires_in.as_ref()).or_else(|| {
+            json_u64(token.expires_on.as_ref())
+                .map(|expires_on| expires_on.saturating_sub(unix_timestamp(&Utc::now())))
+        });
+        token_response.set_expires_in(expires_in.map(Duration::from_secs).as_ref());
+        Ok(token_response)
+    }
+}
+
+/// Read a number which may be serialized as a string.
+fn json_u64(value: Option<&JsonValue>) -> Option<u64> {
+    match value? {
+        JsonValue::Number(number) => number.as_u64(),
+        JsonValue::String(string) => string.parse().ok(),
+        _ => None,
+    }
+}
+
+pub struct AzureClientTokenProvider {
+    source: TokenSource,
+    http_client: HttpClient,
+    refresh_margin: Duration,
     token: RwLock<Option<Token>>,
+    tenant: TenantLabels,
+    /// The scope, or resource, the tokens are requested for.
+    scope: String,
+    /// Added to the local time to get Azure's.
+    clock_offset: ChronoDuration,
+    /// How many fatal refresh failures in a row are tolerated, forever if unset.
+    max_consecutive_failures: Option<u32>,
+    metrics: Arc<TokenMetrics>,
 }
 
 impl AzureClientTokenProvider {
-    pub fn init(settings: &AppSettings) -> Result<Self> {
+    fn init(
+        settings: &AppSettings,
+        tenant: &TenantSettings,
+        metrics: Arc<TokenMetrics>,
+    ) -> Result<Self> {
+        let scope = tenant
+            .azure_scope
+            .clone()
+            .unwrap_or_else(|| format!("{}/{}", settings.cloud.graph_url(), AZURE_SCOPE_PATH));
+        let (source, http_client) = match &tenant.azure_client_credential {
+            ClientCredential::ManagedIdentity => {
+                // The managed identity endpoints take the resource instead of a scope.
+                let managed_identity = ManagedIdentity::new(
+                    tenant.azure_client_id.as_ref().map(|id| id.to_string()),
+                    scope_resource(&scope).to_string(),
+                );
+                // The endpoint is local, so it must never go through a proxy.
+                let http_client = HttpClient::builder()
+                    .user_agent(APP_USER_AGENT)
+                    .timeout(Duration::from_secs(settings.http_timeout_seconds))
+                    .connect_timeout(Duration::from_secs(settings.http_connect_timeout_seconds))
+                    .no_proxy()
+                    .build()?;
+                (TokenSource::ManagedIdentity(managed_identity), http_client)
+            }
+            credential => {
+                let client_id = tenant
+                    .azure_client_id
+                    .clone()
+                    .ok_or_else(|| anyhow!("azure_client_id is required"))?;
+                // Following redirects from the token endpoint could leak the credentials.
+                let http_client = http_client_builder(settings)?
+                    .redirect(RedirectPolicy::none())
+                    .build()?;
+                (
+                    Self::client_credentials(
+                        settings,
+                        tenant,
+                        client_id,
+                        credential,
+                        scope.clone(),
+                    )?,
+                    http_client,
+                )
+            }
+        };
+
+        Ok(Self {
+            source,
+            http_client,
+            refresh_margin: Duration::from_secs(settings.token_refresh_margin_seconds),
+            token: RwLock::new(None),
+            tenant: TenantLabels {
+                tenant_id: tenant.azure_tenant_id.clone(),
+            },
+            scope,
+            clock_offset: ChronoDuration::seconds(settings.clock_offset_seconds),
+            max_consecutive_failures: settings.token_max_consecutive_failures,
+            metrics,
+        })
+    }
+
+    fn client_credentials(
+        settings: &AppSettings,
+        tenant: &TenantSettings,
+        client_id: ClientId,
+        credential: &ClientCredential,
+        scope: String,
+    ) -> Result<TokenSource> {
+        let login_url = settings.cloud.login_url();
+        let (auth_path, token_path) = match settings.oauth_version {
+            OAuthVersion::V1 => (AZURE_V1_AUTH_PATH, AZURE_V1_TOKEN_PATH),
+            OAuthVersion::V2 => (AZURE_AUTH_PATH, AZURE_TOKEN_PATH),
+        };
         let auth_url = AuthUrl::new(format!(
             "{}/{}/{}",
-            AZURE_BASE_URL, &settings.azure_tenant_id, AZURE_AUTH_PATH
+            login_url, &tenant.azure_tenant_id, auth_path
         ))?;
         let token_url = TokenUrl::new(format!(
             "{}/{}/{}",
-            AZURE_BASE_URL, &settings.azure_tenant_id, AZURE_TOKEN_PATH
+            login_url, &tenant.azure_tenant_id, token_path
         ))?;
+        let (client_secret, client_assertion) = match credential {
+            ClientCredential::Secret(secret) => (Some(secret.to_owned()), None),
+            ClientCredential::Certificate { path, password } => (
+                None,
+                Some(ClientAssertion::Certificate(ClientCertificate::load(
+                    path,
+                    password.as_deref(),
+                )?)),
+            ),
+            ClientCredential::FederatedToken(path) => {
+                (None, Some(ClientAssertion::FederatedToken(path.to_owned())))
+            }
+            ClientCredential::ManagedIdentity => {
+                return Err(anyhow!("A managed identity doesn't use client credentials"))
+            }
+        };
         let oauth2_client = Oauth2BasicClient::new(
-            settings.azure_client_id.to_owned(),
-            Some(settings.azure_client_secret.to_owned()),
+            client_id.to_owned(),
+            client_secret,
             auth_url,
-            Some(token_url),
+            Some(token_url.to_owned()),
         );
 
-        Ok(Self {
-            oauth2_client,
-            token: RwLock::new(None),
-        })
+        Ok(TokenSource::ClientCredentials(Box::new(
+            ClientCredentials {
+                oauth2_client,
+                client_id,
+                token_url,
+                client_assertion,
+                scope,
+                oauth_version: settings.oauth_version,
+            },
+        )))
     }
 
-    async fn refresh(&self) -> Result<Instant> {
-        let result = self
-            .oauth2_client
-            .exchange_client_credentials()
-            .add_scope(Scope::new(AZURE_SCOPE.to_string()))
-            .request_async(async_http_client)
-            .await
-            .context("Failed to retrieve Azure token");
+    async fn request_token(&self) -> Result<BasicTokenResponse> {
+        match &self.source {
+            TokenSource::ClientCredentials(flow) => flow.request_token(&self.http_client).await,
+            TokenSource::ManagedIdentity(managed_identity) => {
+                managed_identity.request_token(&self.http_client).await
+            }
+        }
+    }
+
+    /// Warn when the local clock, corrected by the configured offset, is far from Azure's.
+    ///
+    /// The token's `exp` claim is set by Azure's clock while `expires_in` is relative, so the difference between the
+    /// expiration they each imply is the skew.
+    fn check_clock_skew(&self, access_token: &str, expires_in: std::time::Duration) {
+        let Some(exp) = jwt_exp(access_token) else {
+            return;
+        };
+        let local_exp =
+            Utc::now() + self.clock_offset + ChronoDuration::seconds(expires_in.as_secs() as i64);
+        let skew = exp as i64 - local_exp.timestamp();
+        if skew.abs() > CLOCK_SKEW_WARNING_SECONDS {
+            warn!(
+                "The local clock seems off by {} seconds from Azure's according to the token of tenant {}, which skews the time to expiry, consider setting clock_offset_seconds to {}",
+                skew,
+                self.tenant.tenant_id,
+                (self.clock_offset + ChronoDuration::seconds(skew)).num_seconds()
+            );
+        }
+    }
 
+    async fn refresh(&self) -> Result<Instant> {
+        let requested_at = Instant::now();
+        let result = self.request_token().await;
+        self.metrics
+            .acquisition_duration
+            .get_or_create(&self.tenant)
+            .observe(requested_at.elapsed().as_secs_f64());
         match result {
             Err(err) => {
                 *self.token.write().await = None;
+                self.metrics.expires_at.get_or_create(&self.tenant).set(0);
+                self.metrics
+                    .record_failure(&self.tenant, RefreshFailure::from_error(&err));
                 Err(err)
             }
             Ok(token_response) => {
-                let expires_in = Duration::from_secs(
-                    token_response
-                        .expires_in()
-                        .ok_or_else(|| anyhow!("Token doesn't have expiration date"))?
-                        .as_secs(),
-                );
-                let expires_at =
-                    Instant::now() + expires_in - Duration::from_secs(AZURE_TOKEN_MIN_LIFETIME);
+                if let Some(expires_in) = token_response.expires_in() {
+                    self.check_clock_skew(token_response.access_token().secret(), expires_in);
+                }
+                let expires_in = match token_response
+                    .expires_in()
+                    .or_else(|| jwt_expires_in(token_response.access_token().secret()))
+                {
+                    Some(expires_in) => Duration::from_secs(expires_in.as_secs()),
+                    None => {
+                        self.metrics
+                            .record_failure(&self.tenant, RefreshFailure::MissingExpiry);
+                        return Err(anyhow!("Token doesn't have expiration date"));
+                    }
+                };
+                // A margin larger than the token lifetime would have the token refreshed continuously.
+                let refresh_margin = if self.refresh_margin < expires_in {
+                    self.refresh_margin
+                } else {
+                    warn!(
+                        "Token refresh margin of {:?} exceeds the token lifetime of {:?}, using half the lifetime",
+                        self.refresh_margin, expires_in
+                    );
+                    expires_in / 2
+                };
+                let expires_at = Instant::now() + expires_in - refresh_margin;
+                let expires_on = Utc::now() + ChronoDuration::seconds(expires_in.as_secs() as i64);
+                let now = unix_timestamp(&Utc::now());
+                self.metrics
+                    .expires_at
+                    .get_or_create(&self.tenant)
+                    .set(now + expires_in.as_secs());
+                self.metrics
+                    .last_refresh
+                    .get_or_create(&self.tenant)
+                    .set(now);
+                self.metrics
+                    .refresh_successes
+                    .get_or_create(&self.tenant)
+                    .inc();
                 *self.token.write().await = Some(Token {
                     token_response,
                     expires_at,
+                    expires_on,
                 });
                 Ok(expires_at)
             }
         }
     }
 
-    pub async fn work_cache(&self) {
+    /// Retrieve a token a single time, for when there's no need to keep it fresh.
+    pub async fn fetch_token(&self) -> Result<()> {
+        self.refresh().await.with_context(|| {
+            format!(
+                "Failed to retrieve a token for tenant {}",
+                self.tenant.tenant_id
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Keep the token fresh until `shutdown` changes.
+    ///
+    /// Transient failures are retried forever, but the fatal ones, such as invalid credentials, make it give up after
+    /// the configured number of consecutive failures, if any.
+    pub async fn work_cache(&self, mut shutdown: watch::Receiver<()>) -> Result<()> {
+        let mut backoff = Duration::from_secs(AZURE_TOKEN_RETRY_MIN);
+        let mut fatal_failures = 0;
         loop {
-            let deadline = match self.refresh().await {
-                Ok(instant) => instant,
+            let span = info_span!("refresh", tenant_id = %self.tenant.tenant_id, outcome = Empty);
+            let result = self.refresh().instrument(span.clone()).await;
+            span.record("outcome", outcome(&result));
+            let deadline = match result {
+                Ok(instant) => {
+                    backoff = Duration::from_secs(AZURE_TOKEN_RETRY_MIN);
+                    fatal_failures = 0;
+                    // Refreshing a little earlier by a random amount keeps replicas started together from refreshing
+                    // in lockstep, without ever refreshing past the margin.
+                    let interval = instant.saturating_duration_since(Instant::now());
+                    instant
+                        - interval
+                            .mul_f64(AZURE_TOKEN_REFRESH_JITTER * rand::thread_rng().gen::<f64>())
+                }
                 Err(err) => {
-                    warn!("Failed to refresh Azure token: {}", err);
-                    Instant::now() + Duration::from_secs(AZURE_TOKEN_FETCH_RETRY)
+                    fatal_failures = consecutive_fatal_failures(fatal_failures, &err);
+                    if self
+                        .max_consecutive_failures
+                        .is_some_and(|max| fatal_failures >= max)
+                    {
+                        return Err(err.context(format!(
+                            "Gave up refreshing the Azure token for tenant {} after {} consecutive failures",
+                            self.tenant.tenant_id, fatal_failures
+                        )));
+                    }
+                    let delay = with_jitter(backoff);
+                    warn!(
+                        "Failed to refresh Azure token for tenant {}, retrying in {:?}: {}",
+                        self.tenant.tenant_id, delay, err
+                    );
+                    backoff = (backoff * 2).min(Duration::from_secs(AZURE_TOKEN_RETRY_MAX));
+                    Instant::now() + delay
                 }
             };
 
-            tokio::time::sleep_until(deadline).await;
+            let next_refresh = Utc::now()
+                + ChronoDuration::from_std(deadline.saturating_duration_since(Instant::now()))
+                    .unwrap_or_else(|_| ChronoDuration::zero());
+            self.metrics
+                .next_refresh
+                .get_or_create(&self.tenant)
+                .set(unix_timestamp(&next_refresh));
+
+            tokio::select! {
+                () = tokio::time::sleep_until(deadline) => (),
+                // Whether shutdown was requested or the sender dropped, no request will need a token anymore.
+                _ = shutdown.changed() => break,
+            }
         }
+        info!(
+            "Stopped refreshing the Azure token for tenant {}",
+            self.tenant.tenant_id
+        );
+        Ok(())
+    }
+
+    /// When the current token expires, if there is one.
+    async fn token_expiration(&self) -> Option<DateTime<Utc>> {
+        self.token
+            .read()
+            .await
+            .as_ref()
+            .map(|token| token.expires_on)
     }
 
     pub async fn get_secret(&self) -> Result<String> {
@@ -183,104 +979,1611 @@ impl AzureClientTokenProvider {
             .filter(|t| t.expires_at > Instant::now())
         {
             Some(token) => Ok(token.token_response.access_token().secret().clone()),
-            None => Err(anyhow!("No Azure token available")),
+            None => Err(TokenUnavailable.into()),
         }
     }
 }
 
-pub struct AzureGraphClient {
-    token_provider: Arc<AzureClientTokenProvider>,
-    http_client: HttpClient,
+/// The resource a scope such as `https://graph.microsoft.com/.default` is for, without the `/.default` suffix.
+fn scope_resource(scope: &str) -> &str {
+    scope
+        .strip_suffix(AZURE_SCOPE_PATH)
+        .map(|resource| resource.trim_end_matches('/'))
+        .unwrap_or(scope)
 }
 
-impl AzureGraphClient {
-    pub fn with_token_provider(token_provider: Arc<AzureClientTokenProvider>) -> Result<Self> {
-        let http_client = HttpClient::builder()
-            .user_agent(APP_USER_AGENT)
-            .gzip(true)
-            .timeout(Duration::from_secs(2))
-            .https_only(true)
-            .build()?;
+/// Read the `exp` claim of the access token, the Unix timestamp of its expiration.
+///
+/// The token isn't validated, as it was just received from the token endpoint.
+fn jwt_exp(access_token: &str) -> Option<u64> {
+    let payload = access_token.split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    serde_json::from_slice::<JsonValue>(&payload).ok()?["exp"].as_u64()
+}
 
-        Ok(Self {
-            http_client,
-            token_provider,
-        })
-    }
+/// Read how long the access token is valid from its `exp` claim, for the token endpoints which don't return `expires_in`.
+fn jwt_expires_in(access_token: &str) -> Option<std::time::Duration> {
+    let exp = jwt_exp(access_token)?;
+    let expires_in = exp.checked_sub(unix_timestamp(&Utc::now()))?;
+    debug!("Token response without expires_in, using the token's exp claim");
+    Some(std::time::Duration::from_secs(expires_in))
 }
 
-#[async_trait]
-impl PromScraper for AzureGraphClient {
-    async fn scrape(&self) -> Result<Registry> {
-        let mut registry = <Registry>::default();
-        let credentials_metric = Family::<CredentialLabels, Gauge<u64, AtomicU64>>::default();
-        registry.register_with_unit(
-            "credential_expiration_time",
-            "Timestamp of credential expiration",
-            Unit::Seconds,
-            Box::new(credentials_metric.clone()),
-        );
+/// Send a token request with the exporter's own HTTP client, so that it goes through the same proxy as Graph requests.
+async fn send_token_request(
+    http_client: &HttpClient,
+    request: HttpRequest,
+) -> std::result::Result<HttpResponse, Oauth2HttpError<reqwest::Error>> {
+    let response = http_client
+        .request(request.method, request.url.as_str())
+        .headers(request.headers)
+        .body(request.body)
+        .send()
+        .await
+        .map_err(Oauth2HttpError::Reqwest)?;
 
-        let mut url = AZURE_APPLICATIONS_ENDPOINT.to_string();
-        let mut query = &[(
-            "$select",
-            "appId,displayName,keyCredentials,passwordCredentials",
-        )];
+    let status_code = response.status();
+    let headers = response.headers().to_owned();
+    let body = response
+        .bytes()
+        .await
+        .map_err(Oauth2HttpError::Reqwest)?
+        .to_vec();
+    Ok(HttpResponse {
+        status_code,
+        headers,
+        body,
+    })
+}
 
-        loop {
-            let response = self
-                .http_client
-                .get(url)
-                .query(query)
-                .bearer_auth(self.token_provider.get_secret().await?)
-                .send()
-                .await?
-                .error_for_status()?;
-
-            let body = response.json::<ResponsePage>().await?;
-            for app in body.value {
-                for credential in app
-                    .password_credentials
-                    .iter()
-                    .chain(app.key_credentials.iter())
-                {
-                    credentials_metric
-                        .get_or_create(&CredentialLabels {
-                            app_name: app.display_name.to_string(),
-                            app_id: app.app_id.to_string(),
-                            key_id: credential.key_id.to_string(),
-                        })
-                        .set(credential.end_date_time.timestamp() as u64);
-                }
-            }
+/// Start building an HTTP client with the settings shared by the token and Graph requests.
+fn http_client_builder(settings: &AppSettings) -> Result<ClientBuilder> {
+    let mut builder = HttpClient::builder()
+        .user_agent(APP_USER_AGENT)
+        .timeout(Duration::from_secs(settings.http_timeout_seconds))
+        .connect_timeout(Duration::from_secs(settings.http_connect_timeout_seconds));
 
-            if let Some(next_link) = body.next_link {
-                url = next_link.clone();
-                query = &[("", "")];
-            } else {
-                break;
-            }
+    // Setting any proxy disables the ones reqwest picks up from the environment.
+    let no_proxy = settings.no_proxy.as_deref().and_then(NoProxy::from_string);
+    if let Some(url) = &settings.https_proxy {
+        let proxy = Proxy::https(url).with_context(|| format!("Invalid HTTPS proxy: {}", url))?;
+        builder = builder.proxy(proxy.no_proxy(no_proxy.clone()));
+    }
+    if let Some(url) = &settings.http_proxy {
+        let proxy = Proxy::http(url).with_context(|| format!("Invalid HTTP proxy: {}", url))?;
+        builder = builder.proxy(proxy.no_proxy(no_proxy));
+    }
+
+    if let Some(path) = &settings.ca_certificate_path {
+        for certificate in load_root_certificates(path)? {
+            builder = builder.add_root_certificate(certificate);
         }
+    }
+    if let (Some(certificate), Some(key)) = (
+        &settings.proxy_client_cert_path,
+        &settings.proxy_client_key_path,
+    ) {
+        builder = builder.identity(load_client_identity(certificate, key)?);
+    }
 
-        Ok(registry)
+    Ok(builder)
+}
+
+/// Whether the URL points to the local host, such as a mock server.
+fn is_loopback(url: &Url) -> bool {
+    match url.host_str() {
+        Some("localhost") => true,
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback()),
+        None => false,
     }
+}
 
-    async fn ready(&self) -> std::result::Result<String, String> {
-        self.token_provider
-            .get_secret()
-            .await
-            .map(|_| String::from("Ok"))
-            .map_err(|e| format!("Unavailable: {}", e))
+/// No valid token is available yet, or anymore, to call Graph with.
+#[derive(Debug)]
+struct TokenUnavailable;
+
+impl Display for TokenUnavailable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No Azure token available")
+    }
+}
+
+impl std::error::Error for TokenUnavailable {}
+
+/// Graph answered 403 Forbidden, which retrying won't fix.
+#[derive(Debug)]
+struct AccessDenied {
+    url: String,
+    request_ids: String,
+}
+
+impl Display for AccessDenied {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Graph denied access to {} (403 Forbidden, {}), the app likely lacks the Application.Read.All application permission or its admin consent",
+            self.url, self.request_ids
+        )
     }
+}
 
-    fn name(&self) -> &str {
-        "Azure App Secrets"
+impl std::error::Error for AccessDenied {}
+
+/// Tell why a scrape failed from the errors it was caused by.
+fn scrape_error(err: anyhow::Error) -> ScrapeError {
+    if err
+        .chain()
+        .any(|cause| cause.is::<TokenUnavailable>() || cause.is::<AccessDenied>())
+    {
+        return ScrapeError::Auth(err);
+    }
+    let http_error = err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .map(|cause| (cause.status(), cause.is_timeout(), cause.is_decode()));
+    if let Some((status, is_timeout, is_decode)) = http_error {
+        return match status {
+            Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => ScrapeError::Auth(err),
+            Some(StatusCode::TOO_MANY_REQUESTS) => ScrapeError::Throttled(err),
+            Some(status) => ScrapeError::Http(status, err),
+            None if is_timeout => ScrapeError::Timeout(err),
+            None if is_decode => ScrapeError::Decode(err),
+            None => ScrapeError::Network(err),
+        };
+    }
+    // The pages are parsed while they're streamed, so reading the body fails as a JSON I/O error.
+    match err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<serde_json::Error>())
+        .map(serde_json::Error::is_io)
+    {
+        Some(true) => ScrapeError::Network(err),
+        Some(false) => ScrapeError::Decode(err),
+        None => ScrapeError::Other(err),
     }
 }
 
+/// How an operation went, as recorded by its span.
+fn outcome<T>(result: &Result<T>) -> &'static str {
+    match result {
+        Ok(_) => "success",
+        Err(_) => "failure",
+    }
+}
+
+/// Randomize the delay between half and all of `backoff` so that retries don't happen in lockstep.
+fn with_jitter(backoff: Duration) -> Duration {
+    let half = backoff / 2;
+    half + half.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
 #[derive(Clone, Hash, PartialEq, Eq, Encode)]
-struct CredentialLabels {
-    app_id: String,
-    app_name: String,
-    key_id: String,
+struct GraphErrorLabels {
+    tenant_id: String,
+    status: u32,
+}
+
+/// Metrics describing the requests to Graph, shared by the clients of all the tenants.
+struct GraphMetrics {
+    request_errors: Family<GraphErrorLabels, Counter>,
+    request_duration: Family<TenantLabels, Histogram, fn() -> Histogram>,
+}
+
+impl Default for GraphMetrics {
+    fn default() -> Self {
+        Self {
+            request_errors: Family::default(),
+            // From 50ms to about 25s.
+            request_duration: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.05, 2.0, 10))
+            }),
+        }
+    }
+}
+
+impl GraphMetrics {
+    fn register(&self, registry: &mut Registry) {
+        registry.register_with_unit(
+            "graph_request_duration",
+            "Time from sending a Graph request to receiving the response headers",
+            Unit::Seconds,
+            Box::new(self.request_duration.clone()),
+        );
+        registry.register(
+            "graph_request_errors",
+            "Number of Graph requests answered with an error, by HTTP status code",
+            Box::new(self.request_errors.clone()),
+        );
+    }
+}
+
+/// Scrapes the credentials of every configured tenant into a single set of metric families.
+pub struct AzureScraper {
+    clients: Vec<Arc<AzureGraphClient>>,
+    token_metrics: Arc<TokenMetrics>,
+    graph_metrics: Arc<GraphMetrics>,
+    metric_prefix: Option<String>,
+    credential_options: CredentialOptions,
+    cloud: &'static str,
+    /// Bounds how many tenants are scraped at the same time, so that Graph doesn't throttle the scrapes.
+    scrape_permits: Semaphore,
+}
+
+impl AzureScraper {
+    pub fn init(settings: &AppSettings) -> Result<Self> {
+        let token_metrics = Arc::new(TokenMetrics::default());
+        let graph_metrics = Arc::new(GraphMetrics::default());
+        let clients = settings
+            .tenants
+            .iter()
+            .map(|tenant| {
+                let token_provider = Arc::new(AzureClientTokenProvider::init(
+                    settings,
+                    tenant,
+                    token_metrics.clone(),
+                )?);
+                AzureGraphClient::with_token_provider(
+                    token_provider,
+                    graph_metrics.clone(),
+                    settings,
+                )
+                .map(Arc::new)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            clients,
+            token_metrics,
+            graph_metrics,
+            metric_prefix: settings.metric_prefix.clone(),
+            credential_options: CredentialOptions {
+                export_mode: settings.export_mode.clone(),
+                with_name: settings.credential_name_label,
+                expiry_warning: ChronoDuration::days(settings.expiry_warning_days),
+                tag_prefix: settings.tag_label_prefix.clone(),
+                with_sign_in_audience: settings.sign_in_audience_label,
+                max_label_length: settings.max_label_length,
+                expiration_metric_name: settings.expiration_metric_name.clone(),
+            },
+            cloud: settings.cloud.name(),
+            scrape_permits: Semaphore::new(settings.max_concurrent_scrapes),
+        })
+    }
+
+    /// Check that Graph lets every tenant read the applications, once their tokens are available.
+    ///
+    /// The tenants denied access are reported as unready from then on.
+    pub fn check_permissions(&self) -> impl Future<Output = Result<()>> {
+        let clients = self.clients.clone();
+        async move {
+            try_join_all(clients.iter().map(|client| client.check_permissions())).await?;
+            Ok(())
+        }
+    }
+
+    /// The token providers of all the tenants, whose caches must be kept warm.
+    pub fn token_providers(&self) -> Vec<Arc<AzureClientTokenProvider>> {
+        self.clients
+            .iter()
+            .map(|client| client.token_provider.clone())
+            .collect()
+    }
+}
+
+struct AzureGraphClient {
+    token_provider: Arc<AzureClientTokenProvider>,
+    http_client: HttpClient,
+    graph_url: String,
+    object_types: Vec<ObjectType>,
+    /// The `$select` of the requests, only carried by the first one, as the next links already include it.
+    select: String,
+    filter: Option<String>,
+    page_size: Option<String>,
+    exclude_app_name: Option<Regex>,
+    include_app_ids: Option<HashSet<Uuid>>,
+    /// How many apps the last successful scrape recorded, reported by `/status`.
+    apps_seen: AtomicU64,
+    /// Why Graph denied access to the applications at startup, which keeps the tenant unready.
+    permission_error: OnceLock<String>,
+    metrics: Arc<GraphMetrics>,
+}
+
+impl AzureGraphClient {
+    fn with_token_provider(
+        token_provider: Arc<AzureClientTokenProvider>,
+        metrics: Arc<GraphMetrics>,
+        settings: &AppSettings,
+    ) -> Result<Self> {
+        let graph_url = settings
+            .graph_url
+            .as_deref()
+            .unwrap_or_else(|| settings.cloud.graph_url());
+        Self::with_graph_url(token_provider, metrics, settings, graph_url)
+    }
+
+    /// Build a client requesting the Graph API at `graph_url`, such as a mock server.
+    ///
+    /// Plain HTTP is only allowed on the loopback interface, so that the token isn't sent in the clear.
+    fn with_graph_url(
+        token_provider: Arc<AzureClientTokenProvider>,
+        metrics: Arc<GraphMetrics>,
+        settings: &AppSettings,
+        graph_url: &str,
+    ) -> Result<Self> {
+        let graph_url = graph_url.trim_end_matches('/');
+        let parsed_url =
+            Url::parse(graph_url).with_context(|| format!("Invalid Graph URL: {}", graph_url))?;
+        let http_client = http_client_builder(settings)?
+            .gzip(settings.http_compression)
+            .https_only(!is_loopback(&parsed_url))
+            .build()?;
+
+        let mut object_types = vec![ObjectType::Application];
+        if settings.scrape_service_principals {
+            object_types.push(ObjectType::ServicePrincipal);
+        }
+        if settings.scrape_deleted_apps {
+            object_types.push(ObjectType::DeletedApplication);
+        }
+
+        Ok(Self {
+            http_client,
+            token_provider,
+            graph_url: graph_url.to_string(),
+            object_types,
+            select: graph_select(settings),
+            filter: settings.graph_filter.clone(),
+            page_size: settings.page_size.map(|page_size| page_size.to_string()),
+            exclude_app_name: settings.exclude_app_name_regex.clone(),
+            include_app_ids: settings.include_app_ids.clone(),
+            apps_seen: AtomicU64::new(0),
+            permission_error: OnceLock::new(),
+            metrics,
+        })
+    }
+}
+
+impl AzureGraphClient {
+    /// Record the credentials of all the configured object types of the tenant.
+    async fn scrape(&self, metrics: &CredentialMetrics) -> Result<()> {
+        // Export the totals even when no credential matches.
+        let tenant = &self.token_provider.tenant;
+        metrics.expiring_soon.get_or_create(tenant).set(0);
+        metrics.never_expiring.get_or_create(tenant).set(0);
+        metrics.partial.get_or_create(tenant).set(0);
+        // Compared to the credentials' dates, set by Azure.
+        let now = Utc::now() + self.token_provider.clock_offset;
+        let mut progress = ScrapeProgress::default();
+        for object_type in &self.object_types {
+            if let Err(err) = self
+                .scrape_objects(object_type, metrics, now, &mut progress)
+                .await
+            {
+                // Some credentials are better than none, as long as the incompleteness is known.
+                if progress.pages == 0 {
+                    return Err(err);
+                }
+                warn!(
+                    "Scrape of tenant {} interrupted after {} pages, exporting partial results: {:#}",
+                    tenant.tenant_id, progress.pages, err
+                );
+                metrics.partial.get_or_create(tenant).set(1);
+                break;
+            }
+        }
+        // Left out for a tenant without any upcoming expiration, as any value would be misleading.
+        if let Some(end) = progress.earliest_expiration {
+            metrics
+                .earliest_expiry
+                .get_or_create(tenant)
+                .set((end - now).num_seconds() as f64);
+        }
+        self.apps_seen.store(progress.recorded, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Request a single application once a token is available, to find out whether the app has the permissions to
+    /// read them.
+    ///
+    /// Only a denied access is an error, as the other failures may well be transient.
+    async fn check_permissions(&self) -> Result<()> {
+        while self.token_provider.get_secret().await.is_err() {
+            tokio::time::sleep(PERMISSION_CHECK_TOKEN_POLL_INTERVAL).await;
+        }
+        let url = format!("{}/{}", self.graph_url, AZURE_APPLICATIONS_PATH);
+        let tenant_id = &self.token_provider.tenant.tenant_id;
+        match self
+            .fetch_page(&url, Some(&[("$select", "appId"), ("$top", "1")]))
+            .await
+        {
+            Ok(_) => {
+                info!(
+                    "Graph grants access to the applications of tenant {}",
+                    tenant_id
+                );
+                Ok(())
+            }
+            Err(err) if err.is::<AccessDenied>() => {
+                error!("Permission check failed for tenant {}: {}", tenant_id, err);
+                let _ = self.permission_error.set(err.to_string());
+                Err(err.context(format!("Permission check failed for tenant {}", tenant_id)))
+            }
+            Err(err) => {
+                warn!(
+                    "Permission check inconclusive for tenant {}: {:#}",
+                    tenant_id, err
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether the app matches the exclusion rules, for the cases `$filter` can't express.
+    ///
+    /// The allowlist of app ids takes precedence over the name exclusion.
+    fn is_excluded(&self, app: &AzureApp) -> bool {
+        if let Some(include_app_ids) = &self.include_app_ids {
+            return !Uuid::parse_str(&app.app_id).is_ok_and(|id| include_app_ids.contains(&id));
+        }
+        self.exclude_app_name
+            .as_ref()
+            .is_some_and(|regex| regex.is_match(&app.display_name))
+    }
+
+    /// Walk through all the pages of the given object type, recording the credentials of each object.
+    ///
+    /// What was recorded is added to `progress`, even when a page fails.
+    #[instrument(skip_all, fields(object_type = object_type.name(), pages, apps))]
+    async fn scrape_objects(
+        &self,
+        object_type: &ObjectType,
+        metrics: &CredentialMetrics,
+        now: DateTime<Utc>,
+        progress: &mut ScrapeProgress,
+    ) -> Result<()> {
+        let url = format!("{}/{}", self.graph_url, object_type.path());
+        let mut query = vec![("$select", self.select.as_str())];
+        if let Some(filter) = &self.filter {
+            query.push(("$filter", filter));
+        }
+        if let Some(page_size) = &self.page_size {
+            query.push(("$top", page_size));
+        }
+
+        // Apps are recorded while the rest of the page is being parsed.
+        // As the next link is only known once a page is parsed, fetching can't go further ahead.
+        let (sender, mut receiver) = mpsc::channel::<AzureApp>(GRAPH_APPS_BUFFER);
+        let pages = AtomicU64::new(0);
+        let fetched_pages = &pages;
+        let fetch = async move {
+            // The next link already carries the query, including the filter and page size,
+            // so it is only set on the first request.
+            let response = self.fetch_page(&url, Some(&query)).await?;
+            let mut next_link = stream_page(response, sender.clone()).await?;
+            fetched_pages.store(1, Ordering::Relaxed);
+            while let Some(link) = next_link {
+                let response = self.fetch_page(&link, None).await?;
+                next_link = stream_page(response, sender.clone()).await?;
+                fetched_pages.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        let record = async {
+            let mut recorded = 0;
+            let mut without_credentials = 0;
+            let mut earliest_expiration = None;
+            while let Some(app) = receiver.recv().await {
+                if self.is_excluded(&app) {
+                    debug!(
+                        "Skipping excluded app {} ({})",
+                        app.display_name, app.app_id
+                    );
+                    continue;
+                }
+                let earliest = metrics.record(&app, object_type, &self.token_provider.tenant, now);
+                earliest_expiration = earliest_expiration.into_iter().chain(earliest).min();
+                recorded += 1;
+                if app.password_credentials.is_empty() && app.key_credentials.is_empty() {
+                    without_credentials += 1;
+                }
+            }
+            (recorded, without_credentials, earliest_expiration)
+        };
+
+        let (fetched, (recorded, without_credentials, earliest_expiration)) =
+            tokio::join!(fetch, record);
+        let pages = pages.into_inner();
+        progress.pages += pages;
+        progress.recorded += recorded;
+        progress.earliest_expiration = progress
+            .earliest_expiration
+            .into_iter()
+            .chain(earliest_expiration)
+            .min();
+        let labels = ObjectTypeLabels {
+            tenant_id: self.token_provider.tenant.tenant_id.clone(),
+            object_type: object_type.clone(),
+            object_state: object_type.state(),
+        };
+        metrics
+            .applications_scraped
+            .get_or_create(&labels)
+            .set(recorded);
+        metrics
+            .without_credentials
+            .get_or_create(&labels)
+            .set(without_credentials);
+        metrics.pages_fetched.get_or_create(&labels).set(pages);
+        Span::current()
+            .record("pages", pages)
+            .record("apps", recorded);
+        fetched
+    }
+
+    /// Retrieve a single page, waiting and retrying a bounded number of times when throttled by Graph or when the
+    /// request fails on the network.
+    ///
+    /// Each request is sent with its own `client-request-id`, which is logged along with Graph's `request-id` on
+    /// failures, so that they can be looked up by Microsoft support.
+    #[instrument(skip_all, fields(url = %url, status, client_request_id))]
+    async fn fetch_page(&self, url: &str, query: Option<&[(&str, &str)]>) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let client_request_id = Uuid::new_v4();
+            Span::current().record("client_request_id", client_request_id.to_string());
+            let mut request = self.http_client.get(url).header(
+                GRAPH_CLIENT_REQUEST_ID_HEADER,
+                client_request_id.to_string(),
+            );
+            if let Some(query) = query {
+                request = request.query(query);
+            }
+            let request = request.bearer_auth(self.token_provider.get_secret().await?);
+            let sent_at = Instant::now();
+            let response = match request.send().await {
+                Ok(response) => response,
+                // Timeouts and failed connections are usually transient, unlike errors answered by Graph.
+                Err(err)
+                    if (err.is_timeout() || err.is_connect()) && attempt < GRAPH_MAX_RETRIES =>
+                {
+                    attempt += 1;
+                    let delay =
+                        with_jitter(Duration::from_secs(GRAPH_RETRY_BACKOFF << (attempt - 1)));
+                    warn!(
+                        "Graph request failed: {} (client-request-id {}), retrying in {:?} (attempt {}/{})",
+                        err, client_request_id, delay, attempt, GRAPH_MAX_RETRIES
+                    );
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+                Err(err) => {
+                    return Err(
+                        anyhow!(err).context(format!("client-request-id {}", client_request_id))
+                    )
+                }
+            };
+            self.metrics
+                .request_duration
+                .get_or_create(&self.token_provider.tenant)
+                .observe(sent_at.elapsed().as_secs_f64());
+
+            let status = response.status();
+            Span::current().record("status", status.as_u16());
+            let request_ids = format!(
+                "client-request-id {}, request-id {}",
+                client_request_id,
+                response
+                    .headers()
+                    .get(GRAPH_REQUEST_ID_HEADER)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or("unknown")
+            );
+            if status.is_client_error() || status.is_server_error() {
+                self.metrics
+                    .request_errors
+                    .get_or_create(&GraphErrorLabels {
+                        tenant_id: self.token_provider.tenant.tenant_id.clone(),
+                        status: status.as_u16().into(),
+                    })
+                    .inc();
+            }
+            if status == StatusCode::FORBIDDEN {
+                let body = response.text().await.unwrap_or_default();
+                debug!("Graph denied access to {} ({}): {}", url, request_ids, body);
+                return Err(AccessDenied {
+                    url: url.to_string(),
+                    request_ids,
+                }
+                .into());
+            }
+            let delay = match status {
+                StatusCode::TOO_MANY_REQUESTS => Some(
+                    retry_after(&response)
+                        .unwrap_or_else(|| Duration::from_secs(GRAPH_DEFAULT_RETRY_AFTER)),
+                ),
+                StatusCode::SERVICE_UNAVAILABLE => retry_after(&response),
+                _ => None,
+            };
+            match delay {
+                Some(delay) if attempt < GRAPH_MAX_RETRIES => {
+                    attempt += 1;
+                    warn!(
+                        "Graph returned {} ({}), retrying in {:?} (attempt {}/{})",
+                        status, request_ids, delay, attempt, GRAPH_MAX_RETRIES
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                _ => return response.error_for_status().context(request_ids),
+            }
+        }
+    }
+}
+
+/// The properties to request for each object, including the optional ones only when they're exported.
+fn graph_select(settings: &AppSettings) -> String {
+    let mut properties = vec![
+        "appId",
+        "displayName",
+        "keyCredentials",
+        "passwordCredentials",
+    ];
+    if settings.tag_label_prefix.is_some() {
+        properties.push("tags");
+    }
+    if settings.sign_in_audience_label {
+        properties.push("signInAudience");
+    }
+    properties.join(",")
+}
+
+/// Convert a date to a Unix timestamp, clamping dates before the epoch to 0 so the unsigned gauge doesn't wrap around.
+fn unix_timestamp(date: &DateTime<Utc>) -> u64 {
+    u64::try_from(date.timestamp()).unwrap_or_else(|_| {
+        warn!("Credential date {} is before the Unix epoch, using 0", date);
+        0
+    })
+}
+
+/// Parse the delay requested by the `Retry-After` header.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value, Utc::now())
+}
+
+/// Parse a `Retry-After` value, either a number of seconds or an HTTP date, as a delay from `now`.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let seconds = match value.trim().parse::<u64>() {
+        Ok(seconds) => seconds,
+        Err(_) => {
+            let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+            // A date in the past means the request can be retried right away.
+            u64::try_from((date.with_timezone(&Utc) - now).num_seconds()).unwrap_or_default()
+        }
+    };
+    Some(Duration::from_secs(seconds.min(GRAPH_MAX_RETRY_AFTER)))
+}
+
+#[async_trait]
+impl PromScraper for AzureScraper {
+    async fn scrape(&self) -> std::result::Result<Registry, ScrapeError> {
+        let mut registry = new_registry(self.metric_prefix.as_deref());
+        let metrics = CredentialMetrics::register(&mut registry, self.credential_options.clone());
+
+        let scrapes = self.clients.iter().map(|client| {
+            let metrics = &metrics;
+            async move {
+                // The semaphore is never closed.
+                let _permit = self.scrape_permits.acquire().await?;
+                let tenant_id = &client.token_provider.tenant.tenant_id;
+                let span = info_span!("scrape", tenant_id = %tenant_id, outcome = Empty);
+                let result = client.scrape(metrics).instrument(span.clone()).await;
+                span.record("outcome", outcome(&result));
+                result.with_context(|| format!("Failed to scrape tenant {}", tenant_id))
+            }
+        });
+        try_join_all(scrapes).await.map_err(scrape_error)?;
+
+        Ok(registry)
+    }
+
+    /// Ready once every tenant has a token, with the details of each tenant.
+    async fn ready(&self) -> std::result::Result<JsonValue, JsonValue> {
+        let mut ready = true;
+        let mut tenants = Vec::with_capacity(self.clients.len());
+        for client in &self.clients {
+            let token_provider = &client.token_provider;
+            let error = match client.permission_error.get() {
+                Some(error) => Some(error.clone()),
+                None => token_provider
+                    .get_secret()
+                    .await
+                    .err()
+                    .map(|e| e.to_string()),
+            };
+            ready &= error.is_none();
+            tenants.push(TenantStatus {
+                tenant_id: &token_provider.tenant.tenant_id,
+                token_expires_at: token_provider.token_expiration().await,
+                apps_seen: client.apps_seen.load(Ordering::Relaxed),
+                error,
+            });
+        }
+
+        let status = json!({ "tenants": tenants });
+        if ready {
+            Ok(status)
+        } else {
+            Err(status)
+        }
+    }
+
+    fn name(&self) -> &str {
+        "Azure App Secrets"
+    }
+
+    fn register_metrics(&self, registry: &mut Registry) {
+        self.token_metrics.register(registry);
+        self.graph_metrics.register(registry);
+
+        // An info metric per tenant, as a single `Info` only holds one label set.
+        let tenant_info = Family::<TenantInfoLabels, Gauge>::default();
+        for client in &self.clients {
+            let object_types = client
+                .object_types
+                .iter()
+                .map(|object_type| match object_type.state() {
+                    ObjectState::Active => object_type.name().to_string(),
+                    ObjectState::Deleted => format!("deleted {}", object_type.name()),
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            tenant_info
+                .get_or_create(&TenantInfoLabels {
+                    tenant_id: client.token_provider.tenant.tenant_id.clone(),
+                    cloud: self.cloud,
+                    scope: client.token_provider.scope.clone(),
+                    object_types,
+                })
+                .set(1);
+        }
+        registry.register(
+            "azure_tenant_info",
+            "Information about the monitored tenants, always 1",
+            Box::new(tenant_info),
+        );
+    }
+}
+
+/// The state of a tenant, as reported by `/status`.
+#[derive(Serialize)]
+struct TenantStatus<'a> {
+    tenant_id: &'a str,
+    token_expires_at: Option<DateTime<Utc>>,
+    apps_seen: u64,
+    /// Why the tenant isn't ready, if it isn't.
+    error: Option<String>,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq)]
+struct CredentialLabels {
+    tenant_id: String,
+    app_id: String,
+    app_name: String,
+    key_id: String,
+    credential_name: String,
+    /// Only Graph's client secrets have a hint, so the label is left out of the certificates' series.
+    hint: Option<String>,
+    thumbprint: String,
+    tag: String,
+    sign_in_audience: String,
+    credential_type: CredentialType,
+    object_type: ObjectType,
+    object_state: ObjectState,
+}
+
+// Written by hand as the derived implementation can't leave a label out.
+impl Encode for CredentialLabels {
+    fn encode(&self, writer: &mut dyn Write) -> std::result::Result<(), std::io::Error> {
+        let labels: [(&str, Option<&dyn Encode>); 12] = [
+            ("tenant_id", Some(&self.tenant_id)),
+            ("app_id", Some(&self.app_id)),
+            ("app_name", Some(&self.app_name)),
+            ("key_id", Some(&self.key_id)),
+            ("credential_name", Some(&self.credential_name)),
+            ("hint", self.hint.as_ref().map(|hint| hint as &dyn Encode)),
+            ("thumbprint", Some(&self.thumbprint)),
+            ("tag", Some(&self.tag)),
+            ("sign_in_audience", Some(&self.sign_in_audience)),
+            ("credential_type", Some(&self.credential_type)),
+            ("object_type", Some(&self.object_type)),
+            ("object_state", Some(&self.object_state)),
+        ];
+        let present = labels
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value?)));
+        for (index, (name, value)) in present.enumerate() {
+            if index > 0 {
+                writer.write_all(b",")?;
+            }
+            write!(writer, "{}=\"", name)?;
+            value.encode(writer)?;
+            writer.write_all(b"\"")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Encode)]
+struct AppCredentialLabels {
+    tenant_id: String,
+    app_id: String,
+    app_name: String,
+    credential_type: CredentialType,
+    object_type: ObjectType,
+    object_state: ObjectState,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Encode)]
+struct ObjectTypeLabels {
+    tenant_id: String,
+    object_type: ObjectType,
+    object_state: ObjectState,
+}
+
+/// The per-scrape metric families describing the credentials.
+struct CredentialMetrics {
+    /// Absent when only the aggregates are exported.
+    details: Option<CredentialDetails>,
+    per_app: Family<AppCredentialLabels, Gauge>,
+    expiring_soon: Family<TenantLabels, Gauge>,
+    expiring_soon_per_app: Family<AppCredentialLabels, Gauge>,
+    never_expiring: Family<TenantLabels, Gauge>,
+    earliest_expiry: Family<TenantLabels, Gauge<f64, AtomicU64>>,
+    partial: Family<TenantLabels, Gauge>,
+    applications_scraped: Family<ObjectTypeLabels, Gauge>,
+    without_credentials: Family<ObjectTypeLabels, Gauge>,
+    pages_fetched: Family<ObjectTypeLabels, Gauge>,
+    options: CredentialOptions,
+}
+
+/// The metric families with a series per credential.
+struct CredentialDetails {
+    expiration: Family<CredentialLabels, Gauge<u64, AtomicU64>>,
+    start: Family<CredentialLabels, Gauge<u64, AtomicU64>>,
+    time_to_expiry: Family<CredentialLabels, Gauge<f64, AtomicU64>>,
+}
+
+impl CredentialDetails {
+    fn register(registry: &mut Registry, expiration_name: &str) -> Self {
+        let expiration = Family::<CredentialLabels, Gauge<u64, AtomicU64>>::default();
+        registry.register_with_unit(
+            expiration_name,
+            "Timestamp of credential expiration",
+            Unit::Seconds,
+            Box::new(expiration.clone()),
+        );
+        let start = Family::<CredentialLabels, Gauge<u64, AtomicU64>>::default();
+        registry.register_with_unit(
+            "credential_start_time",
+            "Timestamp from which the credential is valid",
+            Unit::Seconds,
+            Box::new(start.clone()),
+        );
+
+        // Floating point, as the gauge goes negative once the credential has expired.
+        let time_to_expiry = Family::<CredentialLabels, Gauge<f64, AtomicU64>>::default();
+        registry.register_with_unit(
+            "credential_time_to_expiry",
+            "Time left until credential expiration, as of the scrape",
+            Unit::Seconds,
+            Box::new(time_to_expiry.clone()),
+        );
+
+        Self {
+            expiration,
+            start,
+            time_to_expiry,
+        }
+    }
+}
+
+/// What the credential metrics include.
+#[derive(Clone)]
+struct CredentialOptions {
+    /// Whether a series is exported per credential, or only the aggregates.
+    export_mode: ExportMode,
+    /// Whether the display names of the credentials are exported, as they add to the label cardinality.
+    with_name: bool,
+    /// How long before their expiration credentials are counted as expiring soon.
+    expiry_warning: ChronoDuration,
+    /// The prefix of the app tags exported as the `tag` label, such as `team:`.
+    tag_prefix: Option<String>,
+    /// Whether the sign-in audience of the apps is exported, telling apart the multi-tenant ones.
+    with_sign_in_audience: bool,
+    /// How many characters of the app and credential names are exported.
+    max_label_length: usize,
+    /// The name of the expiration metric, without the unit.
+    expiration_metric_name: String,
+}
+
+impl CredentialMetrics {
+    /// Record the credentials of the app, returning the earliest upcoming expiration among them.
+    fn record(
+        &self,
+        app: &AzureApp,
+        object_type: &ObjectType,
+        tenant: &TenantLabels,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let mut earliest_expiration = None;
+        // Deleted apps are kept out of the tenant totals, which shouldn't warn about credentials nobody can use.
+        let active = object_type.state() == ObjectState::Active;
+        // The series are told apart by the credential type, but a key id shared across types likely points at an
+        // inconsistency in Graph's answer.
+        let password_key_ids: HashSet<_> = app
+            .password_credentials
+            .iter()
+            .map(|credential| &credential.key_id)
+            .collect();
+        for credential in &app.key_credentials {
+            if password_key_ids.contains(&credential.key_id) {
+                warn!(
+                    "App {} ({}) has both a password and a key credential with key id {}",
+                    app.display_name, app.app_id, credential.key_id
+                );
+            }
+        }
+        let app_name = self.label_value(&app.display_name, app);
+        for (credential_type, credentials) in [
+            (CredentialType::Password, &app.password_credentials),
+            (CredentialType::Key, &app.key_credentials),
+        ] {
+            // Graph shouldn't return the same key twice, but if it does, both would end up in the same series.
+            let mut key_ids = HashSet::with_capacity(credentials.len());
+            let credentials: Vec<_> = credentials
+                .iter()
+                .filter(|credential| {
+                    let first = key_ids.insert(&credential.key_id);
+                    if !first {
+                        warn!(
+                            "App {} ({}) has several credentials with key id {}, only exporting the first one",
+                            app.display_name, app.app_id, credential.key_id
+                        );
+                    }
+                    first
+                })
+                .collect();
+            let app_labels = AppCredentialLabels {
+                tenant_id: tenant.tenant_id.clone(),
+                app_id: app.app_id.to_string(),
+                app_name: app_name.clone(),
+                credential_type: credential_type.clone(),
+                object_type: object_type.clone(),
+                object_state: object_type.state(),
+            };
+            self.per_app
+                .get_or_create(&app_labels)
+                .set(credentials.len() as u64);
+
+            let expiring_soon = credentials
+                .iter()
+                .filter_map(|credential| credential.expiration(now))
+                .filter(|end| *end > now && *end - now <= self.options.expiry_warning)
+                .count() as u64;
+            if expiring_soon > 0 {
+                if active {
+                    self.expiring_soon
+                        .get_or_create(tenant)
+                        .inc_by(expiring_soon);
+                }
+                self.expiring_soon_per_app
+                    .get_or_create(&app_labels)
+                    .set(expiring_soon);
+            }
+
+            let tag = self.tag(app);
+            for credential in credentials {
+                let expiration = credential.expiration(now);
+                match expiration {
+                    _ if !active => (),
+                    None => {
+                        self.never_expiring.get_or_create(tenant).inc();
+                    }
+                    Some(end) if end > now => {
+                        earliest_expiration = earliest_expiration.into_iter().chain([end]).min();
+                    }
+                    Some(_) => (),
+                }
+                let Some(details) = &self.details else {
+                    continue;
+                };
+                let labels = CredentialLabels {
+                    tenant_id: tenant.tenant_id.clone(),
+                    app_name: app_name.clone(),
+                    app_id: app.app_id.to_string(),
+                    key_id: credential.key_id.to_string(),
+                    credential_name: credential
+                        .display_name
+                        .as_ref()
+                        .filter(|_| self.options.with_name)
+                        .map(|name| self.label_value(name, app))
+                        .unwrap_or_default(),
+                    hint: credential.hint.clone(),
+                    thumbprint: match credential_type {
+                        CredentialType::Key => credential.thumbprint().unwrap_or_default(),
+                        CredentialType::Password => String::new(),
+                    },
+                    tag: tag.clone(),
+                    sign_in_audience: app
+                        .sign_in_audience
+                        .as_ref()
+                        .filter(|_| self.options.with_sign_in_audience)
+                        .cloned()
+                        .unwrap_or_default(),
+                    credential_type: credential_type.clone(),
+                    object_type: object_type.clone(),
+                    object_state: object_type.state(),
+                };
+                if let Some(start) = &credential.start_date_time {
+                    details
+                        .start
+                        .get_or_create(&labels)
+                        .set(unix_timestamp(start));
+                }
+                // A far-future timestamp would squash the graphs, so such credentials are only counted.
+                if let Some(end) = expiration {
+                    details
+                        .expiration
+                        .get_or_create(&labels)
+                        .set(unix_timestamp(&end));
+                    details
+                        .time_to_expiry
+                        .get_or_create(&labels)
+                        .set((end - now).num_seconds() as f64);
+                }
+            }
+        }
+        earliest_expiration
+    }
+
+    /// Make a name set in Azure, such as `app`'s, safe to export as a label value.
+    ///
+    /// Control characters, such as newlines, are stripped, and overly long names are truncated, so that a carelessly
+    /// named app doesn't produce malformed or oversized output.
+    fn label_value(&self, name: &str, app: &AzureApp) -> String {
+        let mut value: String = name.chars().filter(|c| !c.is_control()).collect();
+        if let Some((index, _)) = value.char_indices().nth(self.options.max_label_length) {
+            warn!(
+                "Truncating name {:?} of app {} to {} characters",
+                name, app.app_id, self.options.max_label_length
+            );
+            value.truncate(index);
+        }
+        value
+    }
+
+    /// The values of the app tags starting with the configured prefix, comma-separated.
+    fn tag(&self, app: &AzureApp) -> String {
+        match &self.options.tag_prefix {
+            Some(prefix) => app
+                .tags
+                .iter()
+                .filter_map(|tag| tag.strip_prefix(prefix.as_str()))
+                .collect::<Vec<_>>()
+                .join(","),
+            None => String::new(),
+        }
+    }
+
+    fn register(registry: &mut Registry, options: CredentialOptions) -> Self {
+        let details = match options.export_mode {
+            ExportMode::Detailed => Some(CredentialDetails::register(
+                registry,
+                &options.expiration_metric_name,
+            )),
+            ExportMode::Aggregate => None,
+        };
+
+        let per_app = Family::<AppCredentialLabels, Gauge>::default();
+        registry.register(
+            "credentials_per_app",
+            "Number of credentials held by the app",
+            Box::new(per_app.clone()),
+        );
+
+        let expiring_soon = Family::<TenantLabels, Gauge>::default();
+        registry.register(
+            "credentials_expiring_soon",
+            "Number of credentials expiring within the warning window",
+            Box::new(expiring_soon.clone()),
+        );
+        let expiring_soon_per_app = Family::<AppCredentialLabels, Gauge>::default();
+        registry.register(
+            "credentials_expiring_soon_per_app",
+            "Number of credentials of the app expiring within the warning window",
+            Box::new(expiring_soon_per_app.clone()),
+        );
+
+        let never_expiring = Family::<TenantLabels, Gauge>::default();
+        registry.register(
+            "credentials_never_expiring",
+            "Number of credentials without an expiration date or expiring in the far future",
+            Box::new(never_expiring.clone()),
+        );
+
+        let earliest_expiry = Family::<TenantLabels, Gauge<f64, AtomicU64>>::default();
+        registry.register_with_unit(
+            "tenant_earliest_credential_expiry",
+            "Time left until the first upcoming credential expiration in the tenant, as of the scrape",
+            Unit::Seconds,
+            Box::new(earliest_expiry.clone()),
+        );
+
+        let partial = Family::<TenantLabels, Gauge>::default();
+        registry.register(
+            "scrape_partial",
+            "Whether the scrape of the tenant was interrupted, only exporting the objects retrieved until then",
+            Box::new(partial.clone()),
+        );
+
+        let applications_scraped = Family::<ObjectTypeLabels, Gauge>::default();
+        registry.register(
+            "applications_scraped",
+            "Number of objects whose credentials were recorded by the scrape",
+            Box::new(applications_scraped.clone()),
+        );
+        let without_credentials = Family::<ObjectTypeLabels, Gauge>::default();
+        registry.register(
+            "apps_without_credentials",
+            "Number of recorded objects without any credential",
+            Box::new(without_credentials.clone()),
+        );
+        let pages_fetched = Family::<ObjectTypeLabels, Gauge>::default();
+        registry.register(
+            "graph_pages_fetched",
+            "Number of Graph pages fetched by the scrape",
+            Box::new(pages_fetched.clone()),
+        );
+
+        Self {
+            details,
+            per_app,
+            expiring_soon,
+            expiring_soon_per_app,
+            never_expiring,
+            earliest_expiry,
+            partial,
+            applications_scraped,
+            without_credentials,
+            pages_fetched,
+            options,
+        }
+    }
+}
+
+/// The kind of directory object holding the credentials.
+#[derive(Clone, Hash, PartialEq, Eq)]
+enum ObjectType {
+    Application,
+    ServicePrincipal,
+    /// An application in the directory's recycle bin, which can still be restored along with its credentials.
+    DeletedApplication,
+}
+
+impl ObjectType {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Application | Self::DeletedApplication => "application",
+            Self::ServicePrincipal => "servicePrincipal",
+        }
+    }
+
+    fn path(&self) -> &'static str {
+        match self {
+            Self::Application => AZURE_APPLICATIONS_PATH,
+            Self::ServicePrincipal => AZURE_SERVICE_PRINCIPALS_PATH,
+            Self::DeletedApplication => AZURE_DELETED_APPLICATIONS_PATH,
+        }
+    }
+
+    fn state(&self) -> ObjectState {
+        match self {
+            Self::Application | Self::ServicePrincipal => ObjectState::Active,
+            Self::DeletedApplication => ObjectState::Deleted,
+        }
+    }
+}
+
+impl Encode for ObjectType {
+    fn encode(&self, writer: &mut dyn Write) -> std::result::Result<(), std::io::Error> {
+        write!(writer, "{}", self.name())
+    }
+}
+
+/// Whether the object is in use or soft-deleted.
+#[derive(Clone, Hash, PartialEq, Eq)]
+enum ObjectState {
+    Active,
+    Deleted,
+}
+
+impl Encode for ObjectState {
+    fn encode(&self, writer: &mut dyn Write) -> std::result::Result<(), std::io::Error> {
+        let str = match self {
+            Self::Active => "active",
+            Self::Deleted => "deleted",
+        };
+        write!(writer, "{}", str)
+    }
+}
+
+/// Whether a credential is a client secret or a certificate.
+#[derive(Clone, Hash, PartialEq, Eq)]
+enum CredentialType {
+    Password,
+    Key,
+}
+
+impl Encode for CredentialType {
+    fn encode(&self, writer: &mut dyn Write) -> std::result::Result<(), std::io::Error> {
+        let str = match self {
+            Self::Password => "password",
+            Self::Key => "key",
+        };
+        write!(writer, "{}", str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter::encode_registries;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    static TENANT_SETTINGS: &str = r#"
+        azure_tenant_id = "contoso.onmicrosoft.com"
+        azure_client_id = "00000000-0000-0000-0000-000000000001"
+        azure_client_secret = "secret"
+    "#;
+
+    /// A scraper requesting the Graph API at `graph_url`, whose tenant already holds a token.
+    async fn scraper(graph_url: &str) -> AzureScraper {
+        let settings = AppSettings::from_toml(TENANT_SETTINGS);
+        let mut scraper = AzureScraper::init(&settings).unwrap();
+        let token_provider = scraper.token_providers().remove(0);
+        *token_provider.token.write().await = Some(Token {
+            token_response: BasicTokenResponse::new(
+                AccessToken::new("token".to_string()),
+                BasicTokenType::Bearer,
+                EmptyExtraTokenFields {},
+            ),
+            expires_at: Instant::now() + Duration::from_secs(3600),
+            expires_on: Utc::now() + ChronoDuration::hours(1),
+        });
+        let client = AzureGraphClient::with_graph_url(
+            token_provider,
+            scraper.graph_metrics.clone(),
+            &settings,
+            graph_url,
+        )
+        .unwrap();
+        scraper.clients = vec![Arc::new(client)];
+        scraper
+    }
+
+    fn credential(key_id: &str, end_date_time: &str) -> JsonValue {
+        json!({
+            "customKeyIdentifier": null,
+            "displayName": "secret",
+            "endDateTime": end_date_time,
+            "hint": "abc",
+            "keyId": key_id,
+            "startDateTime": "2020-01-01T00:00:00Z",
+        })
+    }
+
+    fn app(
+        app_id: &str,
+        password_credentials: &[JsonValue],
+        key_credentials: &[JsonValue],
+    ) -> JsonValue {
+        json!({
+            "appId": app_id,
+            "displayName": format!("App {}", app_id),
+            "passwordCredentials": password_credentials,
+            "keyCredentials": key_credentials,
+        })
+    }
+
+    /// A page of applications, each with a single client secret.
+    fn page(app_ids: &[&str], next_link: Option<String>) -> JsonValue {
+        let apps: Vec<_> = app_ids
+            .iter()
+            .map(|app_id| {
+                let secret = credential(&format!("{}-secret", app_id), "2030-01-01T00:00:00Z");
+                app(app_id, &[secret], &[])
+            })
+            .collect();
+        match next_link {
+            Some(next_link) => json!({ "value": apps, "@odata.nextLink": next_link }),
+            None => json!({ "value": apps }),
+        }
+    }
+
+    /// Record the credentials of `app`, returning the resulting metrics.
+    fn record(app: JsonValue) -> String {
+        let mut registry = new_registry(None);
+        let options = CredentialOptions {
+            export_mode: ExportMode::Detailed,
+            with_name: true,
+            expiry_warning: ChronoDuration::days(30),
+            tag_prefix: None,
+            with_sign_in_audience: false,
+            max_label_length: 256,
+            expiration_metric_name: "credential_expiration_time".to_string(),
+        };
+        let metrics = CredentialMetrics::register(&mut registry, options);
+        let tenant = TenantLabels {
+            tenant_id: "contoso.onmicrosoft.com".to_string(),
+        };
+        let app = serde_json::from_value(app).unwrap();
+        metrics.record(&app, &ObjectType::Application, &tenant, Utc::now());
+        encode_registries(vec![&registry]).unwrap()
+    }
+
+    /// The samples of the metric family, without their help and type.
+    fn samples<'a>(output: &'a str, name: &str) -> Vec<&'a str> {
+        output
+            .lines()
+            .filter(|line| {
+                line.strip_prefix(name)
+                    .is_some_and(|rest| rest.starts_with('{'))
+            })
+            .collect()
+    }
+
+    fn applications_path() -> String {
+        format!("/{}", AZURE_APPLICATIONS_PATH)
+    }
+
+    #[tokio::test]
+    async fn scrape_follows_next_links() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(applications_path()))
+            .and(query_param("page", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(&["app-2"], None)))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let next_link = format!("{}{}?page=2", server.uri(), applications_path());
+        Mock::given(method("GET"))
+            .and(path(applications_path()))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(page(&["app-1"], Some(next_link))),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let registry = scraper(&server.uri()).await.scrape().await.unwrap();
+        let output = encode_registries(vec![&registry]).unwrap();
+
+        assert!(output.contains(r#"key_id="app-1-secret""#));
+        assert!(output.contains(r#"key_id="app-2-secret""#));
+        assert!(output.contains(
+            r#"applications_scraped{tenant_id="contoso.onmicrosoft.com",object_type="application",object_state="active"} 2"#
+        ));
+        assert!(output.contains(
+            r#"graph_pages_fetched{tenant_id="contoso.onmicrosoft.com",object_type="application",object_state="active"} 2"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn scrape_retries_when_throttled() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(applications_path()))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(applications_path()))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(&["app-1"], None)))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let scraper = scraper(&server.uri()).await;
+        let registry = scraper.scrape().await.unwrap();
+        let output = encode_registries(vec![&registry]).unwrap();
+        assert!(output.contains(r#"key_id="app-1-secret""#));
+
+        let mut own_registry = Registry::default();
+        scraper.register_metrics(&mut own_registry);
+        let own_output = encode_registries(vec![&own_registry]).unwrap();
+        assert!(own_output.contains(
+            r#"graph_request_errors_total{tenant_id="contoso.onmicrosoft.com",status="429"} 1"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn scrape_fails_when_access_is_denied() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(applications_path()))
+            .respond_with(ResponseTemplate::new(403))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let result = scraper(&server.uri()).await.scrape().await;
+
+        assert!(matches!(result, Err(ScrapeError::Auth(_))));
+    }
+
+    #[test]
+    fn duplicate_key_ids_only_keep_the_first_credential() {
+        let first = credential("shared", "2030-01-01T00:00:00Z");
+        let duplicate = credential("shared", "2031-01-01T00:00:00Z");
+        let output = record(app("app-1", &[first, duplicate], &[]));
+
+        let expirations = samples(&output, "credential_expiration_time_seconds");
+        assert_eq!(expirations.len(), 1, "{}", output);
+        assert!(expirations[0].contains(r#"key_id="shared""#));
+        assert!(expirations[0].ends_with(" 1893456000"));
+        let per_app = samples(&output, "credentials_per_app");
+        assert!(per_app.iter().any(
+            |sample| sample.contains(r#"credential_type="password""#) && sample.ends_with(" 1")
+        ));
+    }
+
+    #[test]
+    fn credentials_without_start_date_are_recorded() {
+        let mut secret = credential("secret", "2030-01-01T00:00:00Z");
+        secret["startDateTime"] = JsonValue::Null;
+        let mut certificate = credential("certificate", "2030-01-01T00:00:00Z");
+        certificate.as_object_mut().unwrap().remove("startDateTime");
+        let output = record(app("app-1", &[secret], &[certificate]));
+
+        assert_eq!(
+            samples(&output, "credential_expiration_time_seconds").len(),
+            2
+        );
+        assert!(samples(&output, "credential_start_time_seconds").is_empty());
+    }
+
+    #[test]
+    fn dates_before_the_epoch_are_clamped() {
+        let mut secret = credential("secret", "1961-01-01T00:00:00Z");
+        secret["startDateTime"] = json!("1960-01-01T00:00:00Z");
+        let output = record(app("app-1", &[secret], &[]));
+
+        let start = samples(&output, "credential_start_time_seconds");
+        assert_eq!(start.len(), 1);
+        assert!(start[0].ends_with(" 0"), "{}", start[0]);
+        let expiration = samples(&output, "credential_expiration_time_seconds");
+        assert_eq!(expiration.len(), 1);
+        assert!(expiration[0].ends_with(" 0"), "{}", expiration[0]);
+    }
+
+    #[test]
+    fn far_future_credentials_never_expire() {
+        let secret = credential("secret", "9999-12-31T23:59:59Z");
+        let output = record(app("app-1", &[secret], &[]));
+
+        assert!(samples(&output, "credential_expiration_time_seconds").is_empty());
+        assert_eq!(
+            samples(&output, "credentials_never_expiring"),
+            [r#"credentials_never_expiring{tenant_id="contoso.onmicrosoft.com"} 1"#]
+        );
+    }
+
+    #[test]
+    fn shared_key_ids_are_exported_per_credential_type() {
+        let secret = credential("shared", "2030-01-01T00:00:00Z");
+        let certificate = credential("shared", "2031-01-01T00:00:00Z");
+        let output = record(app("app-1", &[secret], &[certificate]));
+
+        let expirations = samples(&output, "credential_expiration_time_seconds");
+        assert_eq!(expirations.len(), 2, "{}", output);
+        for credential_type in ["password", "key"] {
+            let label = format!(r#"credential_type="{}""#, credential_type);
+            assert!(expirations
+                .iter()
+                .any(|sample| sample.contains(r#"key_id="shared""#) && sample.contains(&label)));
+        }
+    }
+
+    #[test]
+    fn graph_select_only_requests_the_exported_properties() {
+        let base = "appId,displayName,keyCredentials,passwordCredentials";
+        for (settings, expected) in [
+            ("", base.to_string()),
+            (r#"tag_label_prefix = "team:""#, format!("{},tags", base)),
+            (
+                "sign_in_audience_label = true",
+                format!("{},signInAudience", base),
+            ),
+            (
+                "tag_label_prefix = \"team:\"\nsign_in_audience_label = true",
+                format!("{},tags,signInAudience", base),
+            ),
+        ] {
+            let settings = AppSettings::from_toml(&format!("{}\n{}", TENANT_SETTINGS, settings));
+            assert_eq!(graph_select(&settings), expected);
+        }
+    }
+
+    #[test]
+    fn transient_failure_breaks_fatal_streak() {
+        let fatal = || {
+            anyhow::Error::new(TokenRequestError::ServerResponse(BasicErrorResponse::new(
+                BasicErrorResponseType::InvalidClient,
+                None,
+                None,
+            )))
+        };
+        let timeout = anyhow!("Request timed out");
+
+        let count = consecutive_fatal_failures(0, &fatal());
+        assert_eq!(count, 1);
+        let count = consecutive_fatal_failures(count, &timeout);
+        assert_eq!(count, 0);
+        let count = consecutive_fatal_failures(count, &fatal());
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn retry_after_accepts_seconds_and_dates() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        let cases = [
+            ("10", Some(10)),
+            ("3600", Some(GRAPH_MAX_RETRY_AFTER)),
+            ("Wed, 21 Oct 2015 07:28:30 GMT", Some(30)),
+            ("Wed, 21 Oct 2015 09:28:00 GMT", Some(GRAPH_MAX_RETRY_AFTER)),
+            ("Wed, 21 Oct 2015 07:27:00 GMT", Some(0)),
+            ("soon", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(
+                parse_retry_after(value, now),
+                expected.map(Duration::from_secs),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn hint_label_is_only_exported_when_present() {
+        let secret = credential("secret", "2030-01-01T00:00:00Z");
+        let mut certificate = credential("certificate", "2030-01-01T00:00:00Z");
+        certificate["hint"] = JsonValue::Null;
+        let output = record(app("app-1", &[secret], &[certificate]));
+
+        let expirations = samples(&output, "credential_expiration_time_seconds");
+        assert_eq!(expirations.len(), 2, "{}", output);
+        for sample in expirations {
+            if sample.contains(r#"key_id="secret""#) {
+                assert!(sample.contains(r#",hint="abc",thumbprint="#), "{}", sample);
+            } else {
+                assert!(!sample.contains("hint="), "{}", sample);
+                assert!(
+                    sample.contains(r#",credential_name="secret",thumbprint="#),
+                    "{}",
+                    sample
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,159 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use p12::PFX;
+use pem::Pem;
+use reqwest::{Certificate, Identity};
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::path::Path;
+use uuid::Uuid;
+
+static ASSERTION_LIFETIME_MINUTES: i64 = 10;
+/// DER encoding of the PBES2 OID (1.2.840.113549.1.5.13), which the `p12` crate can't decrypt.
+static PBES2_OID: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05, 0x0d,
+];
+
+/// A certificate used to sign the client assertions sent to the token endpoint instead of a client secret.
+pub struct ClientCertificate {
+    /// Base64url-encoded SHA-1 thumbprint of the certificate, sent as the `x5t` header.
+    thumbprint: String,
+    key: EncodingKey,
+}
+
+#[derive(Serialize)]
+struct AssertionClaims<'a> {
+    aud: &'a str,
+    iss: &'a str,
+    sub: &'a str,
+    jti: String,
+    nbf: i64,
+    exp: i64,
+}
+
+impl ClientCertificate {
+    /// Load a certificate and its private key.
+    ///
+    /// Files ending in `.pfx` or `.p12` are read as PKCS#12 and decrypted with `password`.
+    /// Anything else is read as PEM, which must contain both the certificate and an unencrypted RSA key.
+    pub fn load(path: &Path, password: Option<&str>) -> Result<Self> {
+        let content = std::fs::read(path)
+            .with_context(|| format!("Failed to read client certificate {}", path.display()))?;
+        let is_pkcs12 = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("pfx") || ext.eq_ignore_ascii_case("p12"));
+
+        let (certificate, key) = if is_pkcs12 {
+            Self::parse_pkcs12(&content, password.unwrap_or_default())
+        } else {
+            Self::parse_pem(&content)
+        }
+        .with_context(|| format!("Failed to parse client certificate {}", path.display()))?;
+
+        Ok(Self {
+            thumbprint: base64::encode_config(Sha1::digest(certificate), base64::URL_SAFE_NO_PAD),
+            key: EncodingKey::from_rsa_pem(pem::encode(&key).as_bytes())?,
+        })
+    }
+
+    fn parse_pem(content: &[u8]) -> Result<(Vec<u8>, Pem)> {
+        let blocks = pem::parse_many(content)?;
+        let certificate = blocks
+            .iter()
+            .find(|block| block.tag == "CERTIFICATE")
+            .ok_or_else(|| anyhow!("No certificate found"))?
+            .contents
+            .clone();
+        let key = blocks
+            .into_iter()
+            .find(|block| block.tag == "PRIVATE KEY" || block.tag == "RSA PRIVATE KEY")
+            .ok_or_else(|| anyhow!("No unencrypted private key found"))?;
+        Ok((certificate, key))
+    }
+
+    fn parse_pkcs12(content: &[u8], password: &str) -> Result<(Vec<u8>, Pem)> {
+        // The algorithm identifiers aren't encrypted, so this finds the files which would otherwise fail to decrypt
+        // with a misleading error, such as those exported by OpenSSL 3 with its default AES-256 encryption.
+        if content
+            .windows(PBES2_OID.len())
+            .any(|window| window == PBES2_OID)
+        {
+            return Err(anyhow!(
+                "PKCS#12 files encrypted with PBES2 (AES) aren't supported, \
+                 re-export it with `openssl pkcs12 -export -legacy` or use a PEM file instead"
+            ));
+        }
+        let pfx = PFX::parse(content).map_err(|err| anyhow!("Invalid PKCS#12: {:?}", err))?;
+        let certificate = pfx
+            .cert_x509_bags(password)
+            .map_err(|err| anyhow!("Failed to decrypt certificate: {:?}", err))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No certificate found"))?;
+        let key = pfx
+            .key_bags(password)
+            .map_err(|err| anyhow!("Failed to decrypt private key: {:?}", err))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No private key found"))?;
+        Ok((
+            certificate,
+            Pem {
+                tag: String::from("PRIVATE KEY"),
+                contents: key,
+            },
+        ))
+    }
+
+    /// Build a signed client assertion for `client_id`, valid for the token endpoint at `audience`.
+    pub fn assertion(&self, client_id: &str, audience: &str) -> Result<String> {
+        let now = Utc::now();
+        let claims = AssertionClaims {
+            aud: audience,
+            iss: client_id,
+            sub: client_id,
+            jti: Uuid::new_v4().to_string(),
+            nbf: now.timestamp(),
+            exp: (now + Duration::minutes(ASSERTION_LIFETIME_MINUTES)).timestamp(),
+        };
+        let mut header = Header::new(Algorithm::RS256);
+        header.x5t = Some(self.thumbprint.clone());
+
+        jsonwebtoken::encode(&header, &claims, &self.key).context("Failed to sign client assertion")
+    }
+}
+
+/// Load the root certificates from a PEM bundle, to trust a TLS-intercepting proxy for example.
+pub fn load_root_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let content = std::fs::read(path)
+        .with_context(|| format!("Failed to read CA certificates {}", path.display()))?;
+    let certificates = pem::parse_many(&content)
+        .with_context(|| format!("Failed to parse CA certificates {}", path.display()))?
+        .into_iter()
+        .filter(|block| block.tag == "CERTIFICATE")
+        .map(|block| Certificate::from_der(&block.contents))
+        .collect::<reqwest::Result<Vec<_>>>()
+        .with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+
+    if certificates.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path.display()));
+    }
+    Ok(certificates)
+}
+
+/// Load the certificate chain and private key presented to the proxy or the endpoints which require mutual TLS.
+pub fn load_client_identity(certificate_path: &Path, key_path: &Path) -> Result<Identity> {
+    let mut content = std::fs::read(certificate_path).with_context(|| {
+        format!(
+            "Failed to read client certificate {}",
+            certificate_path.display()
+        )
+    })?;
+    content.push(b'\n');
+    content.extend(
+        std::fs::read(key_path)
+            .with_context(|| format!("Failed to read client key {}", key_path.display()))?,
+    );
+    Identity::from_pem(&content).context("Invalid client certificate or key")
+}
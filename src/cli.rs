@@ -0,0 +1,32 @@
+use clap::Parser;
+use std::path::PathBuf;
+
+/// Command line arguments. They take precedence over the environment and the configuration file.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Configuration files, with or without their extension, or directories of configuration files.
+    /// Repeat or separate with commas to layer them, the later ones overriding the earlier ones
+    #[arg(long, env = "AASM_CONFIG_FILE", value_delimiter = ',')]
+    pub config: Vec<PathBuf>,
+
+    /// Port to listen on
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Address to listen on
+    #[arg(long)]
+    pub bind: Option<String>,
+
+    /// Log format: json, pretty or compact
+    #[arg(long)]
+    pub log_format: Option<String>,
+
+    /// Log level or filter directives, such as debug or azure_app_secrets_monitor=trace
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Scrape once, print the metrics and exit instead of serving them, failing if the scrape fails
+    #[arg(long)]
+    pub once: bool,
+}
@@ -1,29 +1,64 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
-use axum::http::{header, StatusCode};
-use axum::response::{Html, IntoResponse, Response};
+use axum::body::Body;
+use axum::extract::Query;
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Redirect, Response};
 use axum::routing::get;
-use axum::Router;
+use axum::{Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+use chrono::{DateTime, Utc};
+#[cfg(unix)]
+use hyper::server::accept;
 use prometheus_client::encoding::text::{encode, Encode};
 use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
 use prometheus_client::metrics::info::Info;
-use prometheus_client::registry::Registry;
-use std::io::{Error, Write};
+use prometheus_client::registry::{Registry, Unit};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind, Write};
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use subtle::ConstantTimeEq;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::signal;
-use tracing::{error, info, warn};
+use tokio::sync::{watch, Mutex};
+use tokio::time::{Duration, Instant, MissedTickBehavior};
+use tower_http::compression::CompressionLayer;
+use tracing::{info, warn};
+
+static DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+static OPENMETRICS_EOF: &str = "# EOF\n";
+/// The seconds Prometheus is told to wait before scraping again when the scraped service is throttling.
+static THROTTLED_RETRY_AFTER: u64 = 60;
+static WARM_UP_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 #[async_trait]
 pub trait PromScraper {
-    async fn scrape(&self) -> Result<Registry>;
+    /// Return a new registry holding the state of the scraped service.
+    ///
+    /// The registry is dropped once served, so it must only hold gauges: counters, which must never go back to 0
+    /// during the exporter's lifetime, are registered by `register_metrics` instead.
+    async fn scrape(&self) -> std::result::Result<Registry, ScrapeError>;
 
     /// Return whether the scraper is ready to go.
-    /// The contained message will be displayed on the `/status` page.
-    async fn ready(&self) -> std::result::Result<String, String>;
+    /// The contained details are included in the `/status` report.
+    async fn ready(&self) -> std::result::Result<JsonValue, JsonValue>;
 
     fn name(&self) -> &str;
+
+    /// Register the scraper's own metrics, which live as long as the exporter instead of a single scrape.
+    fn register_metrics(&self, _registry: &mut Registry) {}
 }
 
 #[derive(Clone, Eq, Hash, PartialEq, Encode)]
@@ -47,10 +82,468 @@ impl Encode for Outcome {
     }
 }
 
+/// Why a scrape failed, so that the failures can be told apart without parsing their messages.
+#[derive(Debug)]
+pub enum ScrapeError {
+    /// The scraped service refused the credentials, or there weren't any to present.
+    Auth(anyhow::Error),
+    /// The scraped service kept asking to slow down.
+    Throttled(anyhow::Error),
+    /// The scraped service answered with an unexpected status.
+    Http(StatusCode, anyhow::Error),
+    /// The scraped service's answer couldn't be parsed.
+    Decode(anyhow::Error),
+    /// The scrape, or one of its requests, took too long.
+    Timeout(anyhow::Error),
+    /// The scraped service couldn't be reached.
+    Network(anyhow::Error),
+    Other(anyhow::Error),
+}
+
+impl ScrapeError {
+    /// The short name of the failure, used as a metric label and in `/status`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Auth(_) => "auth",
+            Self::Throttled(_) => "throttled",
+            Self::Http(_, _) => "http",
+            Self::Decode(_) => "decode",
+            Self::Timeout(_) => "timeout",
+            Self::Network(_) => "network",
+            Self::Other(_) => "other",
+        }
+    }
+}
+
+impl Display for ScrapeError {
+    // Alternate formatting includes the causes, which tell what actually went wrong.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auth(err) => write!(f, "Authentication failed: {:#}", err),
+            Self::Throttled(err) => write!(f, "Throttled: {:#}", err),
+            Self::Http(status, err) => write!(f, "Unexpected HTTP status {}: {:#}", status, err),
+            Self::Decode(err) => write!(f, "Invalid response: {:#}", err),
+            Self::Timeout(err) => write!(f, "Timed out: {:#}", err),
+            Self::Network(err) => write!(f, "Network error: {:#}", err),
+            Self::Other(err) => write!(f, "{:#}", err),
+        }
+    }
+}
+
+// The causes are already part of the message, so they aren't exposed as the source.
+impl std::error::Error for ScrapeError {}
+
+#[derive(Clone, Eq, Hash, PartialEq, Encode)]
+struct ScrapeErrorLabels {
+    kind: &'static str,
+}
+
+/// The exporter's own metrics, which live as long as the exporter.
+///
+/// They're registered once in a registry of their own, which the scrapes don't touch, so that the counters only reset
+/// when the exporter restarts, as told by `process_start_time_seconds`.
+struct ExporterMetrics {
+    success: Family<SuccessMetricLabels, Counter>,
+    errors: Family<ScrapeErrorLabels, Counter>,
+    duration: Gauge<f64, AtomicU64>,
+    cache_stale: Gauge,
+}
+
+impl ExporterMetrics {
+    fn register(registry: &mut Registry) -> Self {
+        let success = Family::<SuccessMetricLabels, Counter>::default();
+        registry.register(
+            "scrape_status",
+            "Whether the scrape was successful",
+            Box::new(success.clone()),
+        );
+        let errors = Family::<ScrapeErrorLabels, Counter>::default();
+        registry.register(
+            "scrape_errors",
+            "Failed scrapes, by kind of failure",
+            Box::new(errors.clone()),
+        );
+        let duration = Gauge::<f64, AtomicU64>::default();
+        registry.register_with_unit(
+            "scrape_duration",
+            "Duration of the last scrape",
+            Unit::Seconds,
+            Box::new(duration.clone()),
+        );
+
+        let cache_stale = Gauge::default();
+        registry.register(
+            "scrape_cache_stale",
+            "Whether the served metrics come from a previous scrape because the last one failed",
+            Box::new(cache_stale.clone()),
+        );
+
+        Self {
+            success,
+            errors,
+            duration,
+            cache_stale,
+        }
+    }
+}
+
+type SharedScrapeResult<T = Arc<Registry>> = std::result::Result<T, Arc<ScrapeError>>;
+
+/// What `/status` reports about the scrapes.
+#[derive(Clone, Default, Serialize)]
+struct ScrapeReport {
+    /// When the last successful scrape finished.
+    last_success: Option<DateTime<Utc>>,
+    /// Why the last scrape failed, if it did.
+    last_error: Option<String>,
+    /// The kind of the last scrape's failure, as in the `scrape_errors` metric.
+    last_error_kind: Option<&'static str>,
+    /// Whether the served metrics come from a previous scrape because the last one failed.
+    serving_cache: bool,
+}
+
+#[derive(Default)]
+struct ScrapeState {
+    /// When the last scrape finished, and its result.
+    last: Option<(Instant, SharedScrapeResult)>,
+    /// When the last successful scrape finished, and its registry.
+    last_success: Option<(Instant, Arc<Registry>)>,
+    /// The metrics of a previous run, loaded from the state file and served until a scrape succeeds.
+    restored: Option<Arc<String>>,
+}
+
+/// The scraped metrics to serve.
+enum ScrapedMetrics {
+    Registry(Arc<Registry>),
+    /// OpenMetrics text restored from the state file.
+    Restored(Arc<String>),
+}
+
+/// Coordinates the scrapes triggered by the `/metrics` requests, or by the background loop.
+///
+/// Concurrent requests share a single scrape instead of each of them hitting the scraped service,
+/// and the last successful result is served for up to `ttl` when a scrape fails.
+struct ScrapeCache<S: PromScraper> {
+    scraper: Arc<S>,
+    state: Mutex<ScrapeState>,
+    /// Kept apart from the state, whose lock is held during the scrapes, so that `/status` doesn't wait for them.
+    report: Mutex<ScrapeReport>,
+    ttl: Duration,
+    /// How long a scrape may take before being abandoned.
+    timeout: Option<Duration>,
+    started_at: Instant,
+    /// How long after startup scrapes are skipped while the scraper isn't ready.
+    startup_grace: Duration,
+    warmed_up: AtomicBool,
+    /// Where the last successful scrape is saved, to be served after a restart.
+    state_file: Option<PathBuf>,
+    /// Whether the scrapes are run by the background loop, the requests only serving their latest result.
+    background: bool,
+}
+
+impl<S: PromScraper> ScrapeCache<S> {
+    fn new(
+        scraper: Arc<S>,
+        ttl: Duration,
+        timeout: Option<Duration>,
+        startup_grace: Duration,
+        state_file: Option<PathBuf>,
+        background: bool,
+    ) -> Self {
+        let restored = state_file.as_deref().and_then(load_state).map(Arc::new);
+        Self {
+            scraper,
+            state: Mutex::new(ScrapeState {
+                restored,
+                ..ScrapeState::default()
+            }),
+            report: Mutex::new(ScrapeReport::default()),
+            ttl,
+            timeout,
+            started_at: Instant::now(),
+            startup_grace,
+            warmed_up: AtomicBool::new(false),
+            state_file,
+            background,
+        }
+    }
+
+    /// Whether scrapes may run, which is once the scraper is ready or the startup grace period is over.
+    ///
+    /// Scraping before then would only fail, for example for lack of a token, and count as a failure.
+    async fn warmed_up(&self) -> bool {
+        if self.warmed_up.load(Ordering::Relaxed) {
+            return true;
+        }
+        if self.started_at.elapsed() >= self.startup_grace || self.scraper.ready().await.is_ok() {
+            self.warmed_up.store(true, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+
+    /// Return the metrics to serve, if any, or the error when the scrape failed and there is no cached result.
+    ///
+    /// Callers arriving while a scrape is in flight wait for it and get its result, unless the scrapes run in the
+    /// background, in which case the latest result is returned right away.
+    async fn scrape(
+        &self,
+        metrics: &ExporterMetrics,
+    ) -> SharedScrapeResult<Option<ScrapedMetrics>> {
+        if !self.warmed_up().await {
+            info!("Scraper not ready yet, skipping the scrape during the startup grace period");
+            return self.serve_restored(metrics).await;
+        }
+
+        let requested_at = Instant::now();
+        let mut state = self.state.lock().await;
+        let result = if self.background {
+            match state.last.as_ref() {
+                Some((_, result)) => result.clone(),
+                None => {
+                    drop(state);
+                    return self.serve_restored(metrics).await;
+                }
+            }
+        } else {
+            let shared = state
+                .last
+                .as_ref()
+                .filter(|(finished_at, _)| *finished_at >= requested_at)
+                .map(|(_, result)| result.clone());
+            match shared {
+                Some(result) => result,
+                None => {
+                    let result = timed_scrape(&*self.scraper, metrics, self.timeout).await;
+                    self.record(&mut state, &result).await;
+                    result
+                }
+            }
+        };
+
+        match result {
+            Ok(registry) => {
+                metrics.cache_stale.set(0);
+                self.report.lock().await.serving_cache = false;
+                Ok(Some(ScrapedMetrics::Registry(registry)))
+            }
+            Err(err) => {
+                let cached = state
+                    .last_success
+                    .as_ref()
+                    .filter(|(finished_at, _)| finished_at.elapsed() < self.ttl)
+                    .map(|(_, registry)| ScrapedMetrics::Registry(Arc::clone(registry)))
+                    .or_else(|| state.restored.clone().map(ScrapedMetrics::Restored));
+                metrics.cache_stale.set(cached.is_some().into());
+                self.report.lock().await.serving_cache = cached.is_some();
+                cached.map(Some).ok_or(err)
+            }
+        }
+    }
+
+    /// Serve the metrics restored from the state file, if any, while no scrape has finished yet.
+    async fn serve_restored(
+        &self,
+        metrics: &ExporterMetrics,
+    ) -> SharedScrapeResult<Option<ScrapedMetrics>> {
+        let restored = self.state.lock().await.restored.clone();
+        metrics.cache_stale.set(restored.is_some().into());
+        self.report.lock().await.serving_cache = restored.is_some();
+        Ok(restored.map(ScrapedMetrics::Restored))
+    }
+
+    /// Run a scrape for the background loop, without holding the state's lock so that the requests aren't held up.
+    async fn refresh(&self, metrics: &ExporterMetrics) {
+        let result = timed_scrape(&*self.scraper, metrics, self.timeout).await;
+        let mut state = self.state.lock().await;
+        self.record(&mut state, &result).await;
+    }
+
+    /// Keep the result of a finished scrape, to be served and reported by `/status`.
+    async fn record(&self, state: &mut ScrapeState, result: &SharedScrapeResult) {
+        let finished_at = Instant::now();
+        let mut report = self.report.lock().await;
+        match result {
+            Ok(registry) => {
+                state.last_success = Some((finished_at, Arc::clone(registry)));
+                state.restored = None;
+                if let Some(path) = &self.state_file {
+                    save_state(path, registry);
+                }
+                report.last_success = Some(Utc::now());
+                report.last_error = None;
+                report.last_error_kind = None;
+            }
+            Err(err) => {
+                report.last_error = Some(err.to_string());
+                report.last_error_kind = Some(err.kind());
+            }
+        }
+        state.last = Some((finished_at, result.clone()));
+    }
+
+    /// Scrape every `interval`, once warmed up, so that the requests are served without waiting for a scrape.
+    async fn scrape_periodically(&self, metrics: &ExporterMetrics, interval: Duration) {
+        while !self.warmed_up().await {
+            tokio::time::sleep(WARM_UP_POLL_INTERVAL).await;
+        }
+        let mut ticks = tokio::time::interval(interval);
+        // A scrape taking longer than the interval pushes the next one back instead of starting it right away.
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            self.refresh(metrics).await;
+        }
+    }
+}
+
+/// Read the metrics saved by a previous run, if any.
+fn load_state(path: &Path) -> Option<String> {
+    match std::fs::read_to_string(path) {
+        // Anything else was likely truncated by a crash while saving.
+        Ok(text) if text.ends_with(OPENMETRICS_EOF) => {
+            info!(
+                "Serving the metrics saved in {} until a scrape succeeds",
+                path.display()
+            );
+            Some(text)
+        }
+        Ok(_) => {
+            warn!("Ignoring incomplete state file {}", path.display());
+            None
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => {
+            warn!("Failed to read state file {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Save the metrics of a successful scrape, to be served after a restart until a scrape succeeds.
+///
+/// The file is replaced atomically so that a crash doesn't leave it half-written.
+fn save_state(path: &Path, registry: &Registry) {
+    let saved = encode_registries(vec![registry]).and_then(|text| {
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, text)?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    });
+    if let Err(err) = saved {
+        warn!("Failed to save state file {}: {:#}", path.display(), err);
+    }
+}
+
+/// Run a scrape, recording its duration and outcome, and abandoning it after `timeout`, if any.
+async fn timed_scrape<S: PromScraper>(
+    scraper: &S,
+    metrics: &ExporterMetrics,
+    timeout: Option<Duration>,
+) -> SharedScrapeResult {
+    let start = Instant::now();
+    let result = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, scraper.scrape())
+            .await
+            .unwrap_or_else(|_| {
+                Err(ScrapeError::Timeout(anyhow!(
+                    "Scrape abandoned after {:?}",
+                    timeout
+                )))
+            }),
+        None => scraper.scrape().await,
+    };
+    let result = result.map(Arc::new).map_err(Arc::new);
+    metrics.duration.set(start.elapsed().as_secs_f64());
+    let outcome = match &result {
+        Ok(_) => Outcome::Success,
+        Err(err) => {
+            warn!("Scrape failed: {}", err);
+            metrics
+                .errors
+                .get_or_create(&ScrapeErrorLabels { kind: err.kind() })
+                .inc();
+            Outcome::Failure
+        }
+    };
+    metrics
+        .success
+        .get_or_create(&SuccessMetricLabels { outcome })
+        .inc();
+    result
+}
+
+/// HTTP Basic credentials protecting the `/metrics` and, optionally, `/status` routes.
+pub struct BasicAuth {
+    /// The expected `username:password` pair.
+    expected: String,
+    protect_status: bool,
+}
+
+impl BasicAuth {
+    pub fn new(username: &str, password: &str, protect_status: bool) -> Self {
+        Self {
+            expected: format!("{}:{}", username, password),
+            protect_status,
+        }
+    }
+
+    fn authorizes(&self, request: &Request<Body>) -> bool {
+        request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| base64::decode(encoded).ok())
+            .is_some_and(|provided| self.expected.as_bytes().ct_eq(&provided).into())
+    }
+}
+
+async fn require_basic_auth(
+    credentials: Arc<BasicAuth>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if credentials.authorizes(&request) {
+        next.run(request).await
+    } else {
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"metrics\"")],
+        )
+            .into_response()
+    }
+}
+
 pub struct Exporter<T: PromScraper> {
     socket: SocketAddr,
-    home_page: Html<String>,
+    home_page: HomeRoute,
     scraper: Arc<T>,
+    cache_ttl: Duration,
+    scrape_timeout: Option<Duration>,
+    fail_on_scrape_error: bool,
+    basic_auth: Option<Arc<BasicAuth>>,
+    tls: Option<TlsFiles>,
+    unix_socket: Option<PathBuf>,
+    shutdown_timeout: Duration,
+    metric_prefix: Option<String>,
+    startup_grace: Duration,
+    state_file: Option<PathBuf>,
+    scrape_interval: Option<Duration>,
+    started_at: DateTime<Utc>,
+}
+
+/// What `/` serves.
+enum HomeRoute {
+    Page(Html<String>),
+    RedirectToMetrics,
+    Disabled,
+}
+
+/// PEM files holding the certificate chain and private key used to serve over TLS.
+struct TlsFiles {
+    certificate: PathBuf,
+    key: PathBuf,
 }
 
 impl<T: PromScraper + Send + Sync + 'static> Exporter<T> {
@@ -61,7 +554,9 @@ impl<T: PromScraper + Send + Sync + 'static> Exporter<T> {
                 <body>\
                     <h1>{name} Exporter</h1>
                     <br />
+                    <p><a href=\"/healthz\">Exporter health</a></p>
                     <p><a href=\"/status\">Exporter status</a></p>
+                    <p><a href=\"/version\">Exporter version</a></p>
                     <p><a href=\"/metrics\">Metrics</a></p>
                 </body>\
             </html>",
@@ -74,86 +569,406 @@ impl<T: PromScraper + Send + Sync + 'static> Exporter<T> {
         Self {
             socket,
             scraper: Arc::new(scraper),
-            home_page,
+            home_page: HomeRoute::Page(home_page),
+            cache_ttl: Duration::ZERO,
+            scrape_timeout: None,
+            fail_on_scrape_error: false,
+            basic_auth: None,
+            tls: None,
+            unix_socket: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            metric_prefix: None,
+            startup_grace: Duration::ZERO,
+            state_file: None,
+            scrape_interval: None,
+            started_at: Utc::now(),
         }
     }
 
-    pub async fn run(&self) {
-        let mut registry = <Registry>::default();
-        let success_metric = Family::<SuccessMetricLabels, Counter>::default();
-        registry.register(
-            "scrape_status",
-            "Whether the scrape was successful",
-            Box::new(success_metric.clone()),
-        );
-        let success_metric = Arc::new(success_metric);
-        let info_metric = Info::new(vec![("version", env!["CARGO_PKG_VERSION"])]);
+    /// Redirect `/` to `/metrics` instead of serving the home page.
+    pub fn redirect_home_page(mut self) -> Self {
+        self.home_page = HomeRoute::RedirectToMetrics;
+        self
+    }
+
+    /// Don't serve anything on `/`, to expose as few routes as possible.
+    pub fn disable_home_page(mut self) -> Self {
+        self.home_page = HomeRoute::Disabled;
+        self
+    }
+
+    /// Serve the last successful scrape for up to `ttl` when a scrape fails.
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Abandon the scrapes taking longer than `timeout`, which then count as failed.
+    pub fn scrape_timeout(mut self, timeout: Duration) -> Self {
+        self.scrape_timeout = Some(timeout);
+        self
+    }
+
+    /// Answer `503 Service Unavailable` on `/metrics` when a scrape fails and no cached result is served, so that
+    /// Prometheus' `up` metric reflects the scraped service's health. The exporter's metrics are still returned.
+    pub fn fail_on_scrape_error(mut self) -> Self {
+        self.fail_on_scrape_error = true;
+        self
+    }
+
+    /// Require HTTP Basic authentication to access the metrics.
+    pub fn basic_auth(mut self, credentials: BasicAuth) -> Self {
+        self.basic_auth = Some(Arc::new(credentials));
+        self
+    }
+
+    /// Serve over TLS using the PEM-encoded certificate chain and private key.
+    pub fn tls(mut self, certificate: PathBuf, key: PathBuf) -> Self {
+        self.tls = Some(TlsFiles { certificate, key });
+        self
+    }
+
+    /// Listen on a Unix domain socket at `path` instead of the TCP socket.
+    pub fn unix_socket(mut self, path: PathBuf) -> Self {
+        self.unix_socket = Some(path);
+        self
+    }
+
+    /// Stop waiting for in-flight requests `timeout` after being asked to shut down.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Prepend `prefix`, followed by an underscore, to the names of the exporter's own metrics.
+    pub fn metric_prefix(mut self, prefix: String) -> Self {
+        self.metric_prefix = Some(prefix);
+        self
+    }
+
+    /// Skip the scrapes for up to `grace` after startup, until the scraper is ready.
+    ///
+    /// Only the exporter's own metrics are served in the meantime, without counting a failed scrape.
+    pub fn startup_grace(mut self, grace: Duration) -> Self {
+        self.startup_grace = grace;
+        self
+    }
+
+    /// Save the last successful scrape to `path`, and serve the one saved by the previous run until a scrape succeeds.
+    pub fn state_file(mut self, path: PathBuf) -> Self {
+        self.state_file = Some(path);
+        self
+    }
+
+    /// Scrape in the background every `interval` instead of on each request, `/metrics` serving the latest result.
+    ///
+    /// This keeps the requests fast when a scrape takes longer than the scraping agent is willing to wait.
+    pub fn scrape_interval(mut self, interval: Duration) -> Self {
+        self.scrape_interval = Some(interval);
+        self
+    }
+
+    /// Serve until a shutdown signal is received, failing when the server can't start or stops on an error.
+    pub async fn run(&self) -> Result<()> {
+        let mut registry = new_registry(self.metric_prefix.as_deref());
+        let metrics = Arc::new(ExporterMetrics::register(&mut registry));
+        self.scraper.register_metrics(&mut registry);
+        let info_metric = Info::new(vec![
+            ("version", env!["CARGO_PKG_VERSION"]),
+            ("git_commit", env!["AASM_GIT_COMMIT"]),
+            ("build_timestamp", env!["AASM_BUILD_TIMESTAMP"]),
+        ]);
         registry.register(
             "azure_app_secrets_monitor_build",
             "Information about the scraper itself",
             Box::new(info_metric),
         );
+        let start_time = Gauge::<u64>::default();
+        start_time.set(u64::try_from(self.started_at.timestamp()).unwrap_or_default());
+        // A standard process metric, which must keep its name regardless of the prefix.
+        let mut process_registry = new_registry(None);
+        process_registry.register_with_unit(
+            "process_start_time",
+            "Timestamp of the exporter's start, which explains the counter resets",
+            Unit::Seconds,
+            Box::new(start_time),
+        );
         let registry = Arc::new(registry);
-        let home_page = self.home_page.clone();
-        let app = Router::new()
-            .route("/", get(|| async { home_page }))
-            .route(
-                "/status",
-                get({
-                    let scraper = Arc::clone(&self.scraper);
-                    move || status(scraper)
-                }),
-            )
-            .route(
-                "/metrics",
-                get({
-                    let scraper = Arc::clone(&self.scraper);
-                    let success_metric = Arc::clone(&success_metric);
-                    let registry = Arc::clone(&registry);
-                    || async move { get_metrics(&*scraper, &success_metric, &registry).await }
-                }),
-            );
-        let server = axum::Server::bind(&self.socket).serve(app.into_make_service());
-        info!("Listening on {}", server.local_addr());
-        let graceful = server.with_graceful_shutdown(shutdown_signal());
-        match graceful.await.map_err(axum::Error::new) {
-            Ok(()) => info!("Exporter is shut down"),
-            Err(err) => error!("Server error: {}", err),
+        let process_registry = Arc::new(process_registry);
+        let scrape_cache = Arc::new(ScrapeCache::new(
+            Arc::clone(&self.scraper),
+            self.cache_ttl,
+            self.scrape_timeout,
+            self.startup_grace,
+            self.state_file.clone(),
+            self.scrape_interval.is_some(),
+        ));
+        if let Some(interval) = self.scrape_interval {
+            let scrape_cache = Arc::clone(&scrape_cache);
+            let metrics = Arc::clone(&metrics);
+            tokio::spawn(async move { scrape_cache.scrape_periodically(&metrics, interval).await });
+        }
+        let status_route = get({
+            let scrape_cache = Arc::clone(&scrape_cache);
+            move || async move { status(&scrape_cache).await }
+        });
+        let metrics_route = get({
+            let scrape_cache = Arc::clone(&scrape_cache);
+            let metrics = Arc::clone(&metrics);
+            let registry = Arc::clone(&registry);
+            let process_registry = Arc::clone(&process_registry);
+            let fail_on_scrape_error = self.fail_on_scrape_error;
+            move |headers: HeaderMap, Query(query): Query<MetricsQuery>| async move {
+                let format = ExpositionFormat::negotiate(&headers);
+                let meta_registries = if query.meta {
+                    vec![&*registry, &*process_registry]
+                } else {
+                    vec![]
+                };
+                get_metrics(
+                    &scrape_cache,
+                    &metrics,
+                    meta_registries,
+                    format,
+                    fail_on_scrape_error,
+                )
+                .await
+            }
+        });
+
+        let mut app = Router::new()
+            .route("/healthz", get(|| async { "Ok" }))
+            .route("/version", get(|| async { Json(VersionReport::default()) }));
+        match &self.home_page {
+            HomeRoute::Page(home_page) => {
+                let home_page = home_page.clone();
+                app = app.route("/", get(|| async { home_page }));
+            }
+            HomeRoute::RedirectToMetrics => {
+                app = app.route("/", get(|| async { Redirect::temporary("/metrics") }))
+            }
+            HomeRoute::Disabled => (),
+        }
+        // Compression is only applied when the client sends a matching `Accept-Encoding`.
+        let metrics_route = metrics_route.layer(CompressionLayer::new());
+        let mut protected = Router::new().route("/metrics", metrics_route);
+        match &self.basic_auth {
+            Some(credentials) => {
+                if credentials.protect_status {
+                    protected = protected.route("/status", status_route);
+                } else {
+                    app = app.route("/status", status_route);
+                }
+                let credentials = Arc::clone(credentials);
+                protected = protected.route_layer(middleware::from_fn(
+                    move |request: Request<Body>, next: Next<Body>| {
+                        require_basic_auth(Arc::clone(&credentials), request, next)
+                    },
+                ));
+            }
+            None => app = app.route("/status", status_route),
+        }
+        let app = app.merge(protected);
+
+        let (shutdown_sender, shutdown_receiver) = watch::channel(());
+        let served = self.serve(app, shutdown_receiver);
+        tokio::pin!(served);
+        let result = tokio::select! {
+            result = &mut served => result,
+            () = shutdown_signal() => {
+                // The receivers are only waiting for the change, so sending can't fail while the server is up.
+                let _ = shutdown_sender.send(());
+                match tokio::time::timeout(self.shutdown_timeout, &mut served).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        warn!(
+                            "Requests still in flight after {:?}, forcing shutdown",
+                            self.shutdown_timeout
+                        );
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        result.context("Server error")?;
+        info!("Exporter is shut down gracefully");
+        Ok(())
+    }
+
+    async fn serve(&self, app: Router, shutdown: watch::Receiver<()>) -> Result<()> {
+        if let Some(path) = &self.unix_socket {
+            return serve_unix(path, app, shutdown).await;
+        }
+
+        match &self.tls {
+            Some(tls) => {
+                let config = RustlsConfig::from_pem_file(&tls.certificate, &tls.key)
+                    .await
+                    .context("Failed to load TLS certificate")?;
+                let handle = Handle::new();
+                tokio::spawn({
+                    let handle = handle.clone();
+                    async move {
+                        shutdown_requested(shutdown).await;
+                        handle.graceful_shutdown(None);
+                    }
+                });
+                info!("Listening on {} with TLS", self.socket);
+                axum_server::bind_rustls(self.socket, config)
+                    .handle(handle)
+                    .serve(app.into_make_service())
+                    .await?;
+            }
+            None => {
+                let server = axum::Server::try_bind(&self.socket)
+                    .with_context(|| format!("Failed to bind to {}", self.socket))?
+                    .serve(app.into_make_service());
+                info!("Listening on {}", server.local_addr());
+                server
+                    .with_graceful_shutdown(shutdown_requested(shutdown))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+async fn serve_unix(path: &Path, app: Router, shutdown: watch::Receiver<()>) -> Result<()> {
+    // A socket left over by a previous run that didn't shut down cleanly would prevent binding.
+    // Anything else at the path is more likely a mistake in the settings, and is left alone.
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?,
+        Ok(_) => {
+            return Err(anyhow!(
+                "{} already exists and isn't a socket",
+                path.display()
+            ))
         }
+        Err(err) if err.kind() == ErrorKind::NotFound => (),
+        Err(err) => return Err(err).with_context(|| format!("Failed to check {}", path.display())),
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind to {}", path.display()))?;
+    let incoming = accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|result| Some(result.map(|(stream, _)| stream)))
+    });
+
+    info!("Listening on {}", path.display());
+    let served = axum::Server::builder(incoming)
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown_requested(shutdown))
+        .await;
+    if let Err(err) = std::fs::remove_file(path) {
+        warn!("Failed to remove socket {}: {}", path.display(), err);
     }
+    Ok(served?)
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_path: &Path, _app: Router, _shutdown: watch::Receiver<()>) -> Result<()> {
+    Err(anyhow!(
+        "Unix domain sockets are not supported on this platform"
+    ))
+}
+
+/// The body of `/version`, describing the build.
+#[derive(Serialize)]
+struct VersionReport {
+    name: &'static str,
+    version: &'static str,
+    git_commit: &'static str,
 }
 
-async fn status<T: PromScraper + Send + Sync + 'static>(scraper: Arc<T>) -> impl IntoResponse {
-    match scraper.ready().await {
-        Ok(msg) => msg.into_response(),
-        Err(err) => (StatusCode::SERVICE_UNAVAILABLE, err).into_response(),
+impl Default for VersionReport {
+    fn default() -> Self {
+        Self {
+            name: env!["CARGO_PKG_NAME"],
+            version: env!["CARGO_PKG_VERSION"],
+            git_commit: env!["AASM_GIT_COMMIT"],
+        }
     }
 }
 
+/// The body of `/status`.
+#[derive(Serialize)]
+struct StatusReport {
+    ready: bool,
+    #[serde(flatten)]
+    scrape: ScrapeReport,
+    scraper: JsonValue,
+}
+
+async fn status<S: PromScraper + Send + Sync + 'static>(scrape_cache: &ScrapeCache<S>) -> Response {
+    let (ready, scraper) = match scrape_cache.scraper.ready().await {
+        Ok(details) => (true, details),
+        Err(details) => (false, details),
+    };
+    let report = StatusReport {
+        ready,
+        scrape: scrape_cache.report.lock().await.clone(),
+        scraper,
+    };
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report)).into_response()
+}
+
+/// The query parameters of `/metrics`.
+#[derive(Deserialize)]
+struct MetricsQuery {
+    /// Whether the exporter's own metrics are included, along with the scraper's.
+    #[serde(default = "include_meta")]
+    meta: bool,
+}
+
+fn include_meta() -> bool {
+    true
+}
+
+/// Serve the scraped metrics, preceded by the exporter's own `meta_registries`.
+///
+/// When the scrape failed without a cached result to fall back to, the status is `200 OK` unless
+/// `fail_on_scrape_error` is set. Then it's `504 Gateway Timeout` if the scrape timed out, and
+/// `503 Service Unavailable` otherwise, with a `Retry-After` hint if the scraped service is throttling.
 async fn get_metrics<S: PromScraper + Send + Sync + 'static>(
-    scraper: &S,
-    success_metric: &Family<SuccessMetricLabels, Counter>,
-    registry: &Registry,
+    scrape_cache: &ScrapeCache<S>,
+    metrics: &ExporterMetrics,
+    meta_registries: Vec<&Registry>,
+    format: ExpositionFormat,
+    fail_on_scrape_error: bool,
 ) -> Response {
-    let mut registries = vec![registry];
-    let scrape_result = scraper.scrape().await;
-    let scrape_registry;
-    let outcome = match scrape_result {
-        Ok(scrape_reg) => {
-            scrape_registry = scrape_reg;
-            registries.push(&scrape_registry);
-            Outcome::Success
-        }
-        Err(err) => {
-            warn!("Scrape failed: {}", err);
-            Outcome::Failure
+    let scraped = scrape_cache.scrape(metrics).await;
+    let mut registries = meta_registries;
+    let mut restored = None;
+    match &scraped {
+        Ok(Some(ScrapedMetrics::Registry(scrape_registry))) => registries.push(scrape_registry),
+        Ok(Some(ScrapedMetrics::Restored(text))) => restored = Some(text.as_str()),
+        Ok(None) | Err(_) => (),
+    }
+    match output_metrics(registries, restored, format) {
+        Ok(mut output) => {
+            if let (Err(err), true) = (&scraped, fail_on_scrape_error) {
+                *output.status_mut() = match err.as_ref() {
+                    ScrapeError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+                    _ => StatusCode::SERVICE_UNAVAILABLE,
+                };
+                if let ScrapeError::Throttled(_) = err.as_ref() {
+                    output.headers_mut().insert(
+                        header::RETRY_AFTER,
+                        HeaderValue::from(THROTTLED_RETRY_AFTER),
+                    );
+                }
+            }
+            output
         }
-    };
-    success_metric
-        .get_or_create(&SuccessMetricLabels { outcome })
-        .inc();
-    match output_metrics(registries) {
-        Ok(output) => output,
         Err(err) => {
             let msg = format!("Metrics output failed: {}", err);
             warn!(msg);
@@ -162,20 +977,120 @@ async fn get_metrics<S: PromScraper + Send + Sync + 'static>(
     }
 }
 
-fn output_metrics(registries: Vec<&Registry>) -> Result<Response> {
+/// The text formats the metrics can be served in.
+#[derive(Clone, Copy)]
+enum ExpositionFormat {
+    OpenMetrics,
+    /// The classic Prometheus format, for older agents which don't handle OpenMetrics.
+    Prometheus,
+}
+
+impl ExpositionFormat {
+    /// Pick the format from the `Accept` header, preferring OpenMetrics unless the client only asks for plain text.
+    fn negotiate(headers: &HeaderMap) -> Self {
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if !accept.contains("application/openmetrics-text") && accept.contains("text/plain") {
+            Self::Prometheus
+        } else {
+            Self::OpenMetrics
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            Self::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+        }
+    }
+}
+
+/// Encode the registries, followed by the OpenMetrics text of the `restored` metrics, if any.
+fn output_metrics(
+    registries: Vec<&Registry>,
+    restored: Option<&str>,
+    format: ExpositionFormat,
+) -> Result<Response> {
+    let mut result = encode_registries(registries)?;
+    if let Some(restored) = restored {
+        // Both end the exposition, which must only happen once.
+        if let Some(len) = result.strip_suffix(OPENMETRICS_EOF).map(str::len) {
+            result.truncate(len);
+        }
+        result.push_str(restored);
+    }
+    if let ExpositionFormat::Prometheus = format {
+        result = to_prometheus_text(&result);
+    }
+    let response = ([(header::CONTENT_TYPE, format.content_type())], result).into_response();
+    Ok(response)
+}
+
+/// Encode the registries as OpenMetrics text.
+pub fn encode_registries(registries: Vec<&Registry>) -> Result<String> {
     let mut buffer = vec![];
     encode(&mut buffer, &registries).context("Registry encoding failed")?;
-    let result =
-        String::from_utf8(buffer).context("Failed to parse UTF-8 from encoded registry")?;
-    let response = (
-        [(
-            header::CONTENT_TYPE,
-            "application/openmetrics-text; version=1.0.0; charset=utf-8",
-        )],
-        result,
-    )
-        .into_response();
-    Ok(response)
+    String::from_utf8(buffer).context("Failed to parse UTF-8 from encoded registry")
+}
+
+/// Scrape once and return the metrics as OpenMetrics text, along with the scraper's own metrics, without serving them.
+pub async fn scrape_once<S: PromScraper>(
+    scraper: &S,
+    metric_prefix: Option<&str>,
+) -> Result<String> {
+    let mut registry = new_registry(metric_prefix);
+    scraper.register_metrics(&mut registry);
+    let scrape_registry = scraper.scrape().await?;
+    encode_registries(vec![&registry, &scrape_registry])
+}
+
+/// Rewrite OpenMetrics text into the classic Prometheus format, as the encoder only produces the former.
+///
+/// The metric families are renamed after their samples for counters and info metrics, which OpenMetrics suffixes with
+/// `_total` and `_info`, and the `# UNIT` and `# EOF` lines, unknown to the classic format, are dropped.
+fn to_prometheus_text(openmetrics: &str) -> String {
+    let mut output = String::with_capacity(openmetrics.len());
+    let mut help = None;
+    for line in openmetrics.lines() {
+        if line.starts_with("# HELP ") {
+            // The encoder always writes the help right before the type, which decides the name.
+            help = line.splitn(4, ' ').nth(3);
+        } else if let Some(declaration) = line.strip_prefix("# TYPE ") {
+            let (name, metric_type) = declaration
+                .split_once(' ')
+                .unwrap_or((declaration, "unknown"));
+            let (name, metric_type) = match metric_type {
+                "counter" => (format!("{}_total", name), "counter"),
+                "info" => (format!("{}_info", name), "gauge"),
+                "unknown" => (name.to_string(), "untyped"),
+                other => (name.to_string(), other),
+            };
+            if let Some(text) = help.take() {
+                output.push_str(&format!("# HELP {} {}\n", name, text));
+            }
+            output.push_str(&format!("# TYPE {} {}\n", name, metric_type));
+        } else if !line.starts_with("# UNIT ") && line != "# EOF" {
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+    output
+}
+
+/// Create a registry whose metric names start with `prefix`, if any.
+pub fn new_registry(prefix: Option<&str>) -> Registry {
+    match prefix {
+        Some(prefix) => Registry::with_prefix(prefix),
+        None => Registry::default(),
+    }
+}
+
+/// Wait until the exporter is asked to shut down.
+async fn shutdown_requested(mut shutdown: watch::Receiver<()>) {
+    // An error means the sender is gone, which only happens once the exporter stopped anyway.
+    let _ = shutdown.changed().await;
 }
 
 // Lifted from https://github.com/tokio-rs/axum/blob/main/examples/graceful-shutdown/src/main.rs
@@ -204,3 +1119,117 @@ async fn shutdown_signal() {
 
     info!("signal received, starting graceful shutdown");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Always succeeds, returning a new registry on each scrape like the actual scrapers.
+    struct StubScraper;
+
+    #[async_trait]
+    impl PromScraper for StubScraper {
+        async fn scrape(&self) -> std::result::Result<Registry, ScrapeError> {
+            let mut registry = new_registry(None);
+            registry.register("stub", "A stub gauge", Box::new(Gauge::<u64>::default()));
+            Ok(registry)
+        }
+
+        async fn ready(&self) -> std::result::Result<JsonValue, JsonValue> {
+            Ok(JsonValue::Null)
+        }
+
+        fn name(&self) -> &str {
+            "Stub"
+        }
+    }
+
+    /// Always fails, with the error built by the given function.
+    struct FailingScraper(fn() -> ScrapeError);
+
+    #[async_trait]
+    impl PromScraper for FailingScraper {
+        async fn scrape(&self) -> std::result::Result<Registry, ScrapeError> {
+            Err((self.0)())
+        }
+
+        async fn ready(&self) -> std::result::Result<JsonValue, JsonValue> {
+            Ok(JsonValue::Null)
+        }
+
+        fn name(&self) -> &str {
+            "Failing"
+        }
+    }
+
+    async fn failed_metrics(error: fn() -> ScrapeError) -> Response {
+        let mut registry = Registry::default();
+        let metrics = ExporterMetrics::register(&mut registry);
+        let scrape_cache = ScrapeCache::new(
+            Arc::new(FailingScraper(error)),
+            Duration::ZERO,
+            None,
+            Duration::ZERO,
+            None,
+            false,
+        );
+        get_metrics(
+            &scrape_cache,
+            &metrics,
+            vec![&registry],
+            ExpositionFormat::OpenMetrics,
+            true,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn failed_scrape_status_depends_on_error() {
+        let response = failed_metrics(|| ScrapeError::Timeout(anyhow!("slow"))).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(response.headers().get(header::RETRY_AFTER).is_none());
+
+        let response = failed_metrics(|| ScrapeError::Throttled(anyhow!("busy"))).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+
+        let response = failed_metrics(|| ScrapeError::Network(anyhow!("down"))).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().get(header::RETRY_AFTER).is_none());
+    }
+
+    #[tokio::test]
+    async fn scrape_status_keeps_increasing() {
+        let mut registry = Registry::default();
+        let metrics = ExporterMetrics::register(&mut registry);
+        let scrape_cache = ScrapeCache::new(
+            Arc::new(StubScraper),
+            Duration::ZERO,
+            None,
+            Duration::ZERO,
+            None,
+            false,
+        );
+
+        for count in 1..=3 {
+            assert!(scrape_cache.scrape(&metrics).await.is_ok());
+            let output = encode_registries(vec![&registry]).unwrap();
+            let expected = format!("scrape_status_total{{outcome=\"success\"}} {}\n", count);
+            assert!(output.contains(&expected), "{}", output);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serve_unix_leaves_other_files_alone() {
+        let path = std::env::temp_dir().join(format!("aasm-test-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "keep").unwrap();
+        let (_shutdown_sender, shutdown_receiver) = watch::channel(());
+
+        let result = serve_unix(&path, Router::new(), shutdown_receiver).await;
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,36 +1,177 @@
 mod azure;
+mod certificate;
+mod cli;
 mod exporter;
 mod settings;
 
-use crate::azure::{AzureClientTokenProvider, AzureGraphClient};
-use crate::exporter::Exporter;
-use crate::settings::AppSettings;
-use anyhow::Result;
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
-use std::sync::Arc;
+use crate::azure::AzureScraper;
+use crate::cli::Cli;
+use crate::exporter::{scrape_once, BasicAuth, Exporter};
+use crate::settings::{AppSettings, HomePage, LogFormat, PermissionCheck};
+use anyhow::{Context, Result};
+use clap::Parser;
+use futures_util::future::try_join_all;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::{self, Tracer};
+use opentelemetry_sdk::{runtime, Resource};
+use std::future::pending;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::watch;
 use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, EnvFilter};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt().json().finish().init();
+    let cli = Cli::parse();
+    let settings = AppSettings::fetch(&cli)?;
 
-    let settings = AppSettings::fetch()?;
+    let log_filter = EnvFilter::try_new(&settings.log_level)
+        .with_context(|| format!("Invalid log level: {}", settings.log_level))?;
+    let fmt_layer = match settings.log_format {
+        LogFormat::Json => fmt::layer().json().boxed(),
+        LogFormat::Pretty => fmt::layer().pretty().boxed(),
+        LogFormat::Compact => fmt::layer().compact().boxed(),
+    };
+    let otel_layer = match &settings.otel_endpoint {
+        Some(endpoint) => Some(tracing_opentelemetry::layer().with_tracer(otel_tracer(endpoint)?)),
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(fmt_layer)
+        .with(otel_layer)
+        .init();
 
-    let token_provider = Arc::new(AzureClientTokenProvider::init(&settings)?);
-    let azure_client = AzureGraphClient::with_token_provider(token_provider.clone())?;
+    let azure_scraper = AzureScraper::init(&settings)?;
+    if cli.once {
+        let result = run_once(&azure_scraper, settings.metric_prefix.as_deref()).await;
+        if settings.otel_endpoint.is_some() {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+        return result;
+    }
+    let (shutdown_sender, shutdown_receiver) = watch::channel(());
+    let token_tasks: Vec<_> = azure_scraper
+        .token_providers()
+        .into_iter()
+        .map(|token_provider| {
+            let shutdown = shutdown_receiver.clone();
+            tokio::task::spawn(async move { token_provider.work_cache(shutdown).await })
+        })
+        .collect();
+    let token_refresh = try_join_all(
+        token_tasks
+            .into_iter()
+            .map(|task| async { task.await.context("Token refresh task failed")? }),
+    );
+    tokio::pin!(token_refresh);
+    let permission_check = azure_scraper.check_permissions();
+    let permission_check_mode = settings.permission_check;
+    // Only resolves when the exporter must exit.
+    let permission_check = async move {
+        match permission_check_mode {
+            PermissionCheck::Disabled => (),
+            PermissionCheck::Unready => {
+                let _ = permission_check.await;
+            }
+            PermissionCheck::Exit => {
+                if let Err(err) = permission_check.await {
+                    return err;
+                }
+            }
+        }
+        pending().await
+    };
 
-    tokio::task::spawn(async move {
-        token_provider.work_cache().await;
-    });
+    let listen = SocketAddr::new(settings.bind_address, settings.port);
+    let mut exporter = Exporter::new(listen, azure_scraper)
+        .cache_ttl(Duration::from_secs(settings.scrape_cache_ttl_seconds))
+        .shutdown_timeout(Duration::from_secs(settings.shutdown_timeout_seconds))
+        .startup_grace(Duration::from_secs(settings.startup_grace_seconds));
+    if let (Some(username), Some(password)) =
+        (&settings.metrics_username, &settings.metrics_password)
+    {
+        exporter = exporter.basic_auth(BasicAuth::new(username, password, settings.protect_status));
+    }
+    match settings.home_page {
+        HomePage::Html => (),
+        HomePage::Redirect => exporter = exporter.redirect_home_page(),
+        HomePage::Disabled => exporter = exporter.disable_home_page(),
+    }
+    if let Some(timeout) = settings.scrape_timeout_seconds {
+        exporter = exporter.scrape_timeout(Duration::from_secs(timeout));
+    }
+    if let Some(interval) = settings.scrape_interval_seconds {
+        exporter = exporter.scrape_interval(Duration::from_secs(interval));
+    }
+    if let Some(path) = settings.state_file {
+        exporter = exporter.state_file(path);
+    }
+    if settings.fail_metrics_on_scrape_error {
+        exporter = exporter.fail_on_scrape_error();
+    }
+    if let Some(prefix) = &settings.metric_prefix {
+        exporter = exporter.metric_prefix(prefix.clone());
+    }
+    if let Some(path) = settings.listen_unix_socket {
+        exporter = exporter.unix_socket(path);
+    }
+    if let (Some(certificate), Some(key)) = (settings.tls_cert_path, settings.tls_key_path) {
+        exporter = exporter.tls(certificate, key);
+    }
 
-    let listen: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), settings.port);
-    let exporter = Exporter::new(listen, azure_client);
+    let result = tokio::select! {
+        result = exporter.run() => {
+            // The tokens are kept fresh until the requests in flight are done.
+            // Sending can't fail, as `shutdown_receiver` is still around.
+            let _ = shutdown_sender.send(());
+            match result {
+                Ok(()) => token_refresh.await,
+                Err(err) => Err(err),
+            }
+        }
+        // The tasks only stop by themselves when giving up, which is fatal.
+        result = &mut token_refresh => result,
+        err = permission_check => Err(err),
+    };
 
-    exporter.run().await;
+    if settings.otel_endpoint.is_some() {
+        // Flushes the spans which haven't been exported yet.
+        opentelemetry::global::shutdown_tracer_provider();
+    }
 
+    result.map(|_| ())
+}
+
+/// Scrape a single time and print the metrics, for validating the credentials and permissions.
+async fn run_once(azure_scraper: &AzureScraper, metric_prefix: Option<&str>) -> Result<()> {
+    for token_provider in azure_scraper.token_providers() {
+        token_provider.fetch_token().await?;
+    }
+    let metrics = scrape_once(azure_scraper, metric_prefix).await?;
+    print!("{}", metrics);
     Ok(())
 }
 
+/// Build a tracer exporting the spans over OTLP/HTTP to `endpoint`, in batches.
+fn otel_tracer(endpoint: &str) -> Result<Tracer> {
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![
+            KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ])))
+        .install_batch(runtime::TokioCurrentThread)
+        .context("Failed to set up the OpenTelemetry exporter")
+}
+
 // use graph_rs_sdk::client::Graph;
 // use graph_rs_sdk::oauth::OAuth;
 //
@@ -1,22 +1,361 @@
-use anyhow::{anyhow, Result};
-use config::{Config, Environment, File};
+use crate::azure::AzureCloud;
+use crate::cli::Cli;
+use anyhow::{anyhow, Context, Result};
+use config::{Config, ConfigError, Environment, File};
 use oauth2::{ClientId, ClientSecret};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use uuid::Uuid;
 
+static MAX_PAGE_SIZE: u16 = 999;
+static DEFAULT_CONFIG_FILE: &str = "config";
+static AZURE_FEDERATED_TOKEN_FILE_VAR: &str = "AZURE_FEDERATED_TOKEN_FILE";
+static DEFAULT_CLOUD: &str = "public";
+static DEFAULT_OAUTH_VERSION: &str = "v2";
+static DEFAULT_BIND_ADDRESS: &str = "::";
 static DEFAULT_PORT: u16 = 9912;
+static DEFAULT_HTTP_TIMEOUT_SECONDS: u64 = 30;
+static DEFAULT_HTTP_CONNECT_TIMEOUT_SECONDS: u64 = 10;
+static DEFAULT_TOKEN_REFRESH_MARGIN_SECONDS: u64 = 60;
+static DEFAULT_LOG_FORMAT: &str = "json";
+static DEFAULT_HOME_PAGE: &str = "html";
+static DEFAULT_EXPORT_MODE: &str = "detailed";
+static DEFAULT_PERMISSION_CHECK: &str = "disabled";
+static DEFAULT_EXPIRATION_METRIC_NAME: &str = "credential_expiration_time";
+static DEFAULT_LOG_LEVEL: &str = "info";
+static DEFAULT_EXPIRY_WARNING_DAYS: i64 = 30;
+static DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: u64 = 10;
+static DEFAULT_STARTUP_GRACE_SECONDS: u64 = 30;
+static DEFAULT_MAX_CONCURRENT_SCRAPES: u64 = 4;
+static DEFAULT_MAX_LABEL_LENGTH: u64 = 256;
 
-pub struct AppSettings {
-    pub azure_client_id: ClientId,
-    pub azure_client_secret: ClientSecret,
+/// How the exporter authenticates against Azure AD.
+pub enum ClientCredential {
+    Secret(ClientSecret),
+    Certificate {
+        path: PathBuf,
+        password: Option<String>,
+    },
+    /// A token issued by a trusted OIDC provider, such as a Kubernetes service account token, read from this file.
+    FederatedToken(PathBuf),
+    /// The managed identity of the Azure VM or App Service, user-assigned if the client id is set.
+    ManagedIdentity,
+}
+
+pub enum LogFormat {
+    Json,
+    Pretty,
+    Compact,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "pretty" => Ok(Self::Pretty),
+            "compact" => Ok(Self::Compact),
+            _ => Err(anyhow!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+/// The version of the Azure AD token endpoint used by the client credentials flow.
+#[derive(Clone, Copy)]
+pub enum OAuthVersion {
+    /// The legacy endpoint, which takes a resource instead of a scope.
+    V1,
+    V2,
+}
+
+impl FromStr for OAuthVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "v1" => Ok(Self::V1),
+            "v2" => Ok(Self::V2),
+            _ => Err(anyhow!("Unknown OAuth version: {}", s)),
+        }
+    }
+}
+
+/// Which credential metrics are exported.
+#[derive(Clone)]
+pub enum ExportMode {
+    /// A series per credential, along with the aggregates.
+    Detailed,
+    /// Only the counts per app and tenant, for tenants with too many credentials to keep a series for each.
+    Aggregate,
+}
+
+impl FromStr for ExportMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "detailed" => Ok(Self::Detailed),
+            "aggregate" => Ok(Self::Aggregate),
+            _ => Err(anyhow!("Unknown export mode: {}", s)),
+        }
+    }
+}
+
+/// What happens when the startup check finds that Graph denies reading the applications.
+#[derive(Clone, Copy)]
+pub enum PermissionCheck {
+    /// No check is made, the permission problems only showing when scraping.
+    Disabled,
+    /// The exporter stays unready.
+    Unready,
+    /// The exporter exits with an error.
+    Exit,
+}
+
+impl FromStr for PermissionCheck {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "disabled" => Ok(Self::Disabled),
+            "unready" => Ok(Self::Unready),
+            "exit" => Ok(Self::Exit),
+            _ => Err(anyhow!("Unknown permission check mode: {}", s)),
+        }
+    }
+}
+
+/// What `/` serves.
+pub enum HomePage {
+    /// A page linking to the other routes.
+    Html,
+    /// A redirection to `/metrics`.
+    Redirect,
+    /// Nothing, answering `404 Not Found`.
+    Disabled,
+}
+
+impl FromStr for HomePage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "html" => Ok(Self::Html),
+            "redirect" => Ok(Self::Redirect),
+            "disabled" => Ok(Self::Disabled),
+            _ => Err(anyhow!("Unknown home page mode: {}", s)),
+        }
+    }
+}
+
+/// The identity used to monitor a single tenant.
+pub struct TenantSettings {
+    /// Always set, except for the system-assigned managed identity.
+    pub azure_client_id: Option<ClientId>,
+    pub azure_client_credential: ClientCredential,
     pub azure_tenant_id: String,
+    /// Overrides the scope requested for the tokens, which defaults to the cloud's Graph.
+    pub azure_scope: Option<String>,
+}
+
+impl TenantSettings {
+    /// Read a tenant's settings, whose keys all start with `prefix`.
+    fn fetch(config: &Config, prefix: &str) -> Result<Self> {
+        let key = |name: &str| format!("{}{}", prefix, name);
+
+        let azure_tenant_id = validate_tenant_id(config.get_string(&key("azure_tenant_id"))?)?;
+        let azure_client_id = get_optional::<ClientId>(config, &key("azure_client_id"))?;
+        let azure_scope = get_optional::<String>(config, &key("azure_scope"))?;
+        if azure_scope.as_deref().is_some_and(str::is_empty) {
+            return Err(anyhow!("azure_scope must not be empty"));
+        }
+        match get_optional::<String>(config, &key("auth_method"))?.as_deref() {
+            None | Some("client_credentials") => (),
+            Some("managed_identity") => {
+                return Ok(Self {
+                    azure_client_id,
+                    azure_client_credential: ClientCredential::ManagedIdentity,
+                    azure_tenant_id,
+                    azure_scope,
+                })
+            }
+            Some(method) => return Err(anyhow!("Unknown auth method: {}", method)),
+        }
+        if azure_client_id.is_none() {
+            return Err(anyhow!("azure_client_id is required"));
+        }
+
+        let azure_client_secret = match (
+            get_optional_secret(config, &key("azure_client_secret"))?.map(ClientSecret::new),
+            get_optional::<PathBuf>(config, &key("azure_client_secret_file"))?,
+        ) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "azure_client_secret and azure_client_secret_file are mutually exclusive"
+                ))
+            }
+            (Some(secret), None) => Some(secret),
+            (None, Some(path)) => Some(ClientSecret::new(read_secret_file(&path)?)),
+            (None, None) => None,
+        };
+        let azure_client_credential = match (
+            azure_client_secret,
+            get_optional::<String>(config, &key("azure_client_certificate_path"))?,
+            get_optional::<PathBuf>(config, &key("azure_federated_token_file"))?,
+        ) {
+            (Some(secret), None, None) => ClientCredential::Secret(secret),
+            (None, Some(path), None) => ClientCredential::Certificate {
+                path: path.into(),
+                password: get_optional_secret(config, &key("azure_client_certificate_password"))?,
+            },
+            (None, None, Some(path)) => ClientCredential::FederatedToken(path),
+            // The Azure Workload Identity webhook projects the token and points to it with this variable.
+            (None, None, None) => match std::env::var_os(AZURE_FEDERATED_TOKEN_FILE_VAR) {
+                Some(path) => ClientCredential::FederatedToken(path.into()),
+                None => {
+                    return Err(anyhow!(
+                        "One of azure_client_secret, azure_client_secret_file, azure_client_certificate_path or azure_federated_token_file is required"
+                    ))
+                }
+            },
+            _ => {
+                return Err(anyhow!(
+                    "A client secret, azure_client_certificate_path and azure_federated_token_file are mutually exclusive"
+                ))
+            }
+        };
+
+        Ok(Self {
+            azure_client_id,
+            azure_client_credential,
+            azure_tenant_id,
+            azure_scope,
+        })
+    }
+}
+
+pub struct AppSettings {
+    pub tenants: Vec<TenantSettings>,
+    pub cloud: AzureCloud,
+    /// Overrides the base URL of the Graph API, which defaults to the cloud's.
+    pub graph_url: Option<String>,
+    pub oauth_version: OAuthVersion,
+    pub bind_address: IpAddr,
     pub port: u16,
+    pub http_timeout_seconds: u64,
+    pub http_connect_timeout_seconds: u64,
+    pub https_proxy: Option<String>,
+    pub http_proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    pub ca_certificate_path: Option<PathBuf>,
+    pub proxy_client_cert_path: Option<PathBuf>,
+    pub proxy_client_key_path: Option<PathBuf>,
+    pub http_compression: bool,
+    pub scrape_service_principals: bool,
+    pub scrape_deleted_apps: bool,
+    pub graph_filter: Option<String>,
+    pub page_size: Option<u16>,
+    pub exclude_app_name_regex: Option<Regex>,
+    /// When set, only the apps with these ids are monitored.
+    pub include_app_ids: Option<HashSet<Uuid>>,
+    pub credential_name_label: bool,
+    pub export_mode: ExportMode,
+    pub expiry_warning_days: i64,
+    /// Added to the local time to get Azure's, for hosts whose clock drifts.
+    pub clock_offset_seconds: i64,
+    pub tag_label_prefix: Option<String>,
+    pub sign_in_audience_label: bool,
+    pub max_label_length: usize,
+    pub scrape_cache_ttl_seconds: u64,
+    pub scrape_timeout_seconds: Option<u64>,
+    pub scrape_interval_seconds: Option<u64>,
+    pub state_file: Option<PathBuf>,
+    pub max_concurrent_scrapes: usize,
+    pub fail_metrics_on_scrape_error: bool,
+    pub metrics_username: Option<String>,
+    pub metrics_password: Option<String>,
+    pub protect_status: bool,
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    pub listen_unix_socket: Option<PathBuf>,
+    pub shutdown_timeout_seconds: u64,
+    pub startup_grace_seconds: u64,
+    pub token_refresh_margin_seconds: u64,
+    pub token_max_consecutive_failures: Option<u32>,
+    pub permission_check: PermissionCheck,
+    pub expiration_metric_name: String,
+    pub log_format: LogFormat,
+    pub home_page: HomePage,
+    pub log_level: String,
+    pub otel_endpoint: Option<String>,
+    pub metric_prefix: Option<String>,
 }
 
 impl AppSettings {
-    pub fn fetch() -> Result<Self> {
+    /// Layer the settings from, by order of precedence, the command line, the environment, the configuration files
+    /// and the defaults.
+    pub fn fetch(cli: &Cli) -> Result<Self> {
+        let config_files = if cli.config.is_empty() {
+            vec![File::with_name(DEFAULT_CONFIG_FILE).required(false)]
+        } else {
+            let mut config_files = vec![];
+            for path in &cli.config {
+                for file in config_file_paths(path)? {
+                    config_files.push(File::from(file.as_path()).required(true));
+                }
+            }
+            config_files
+        };
         let config = Config::builder()
+            .set_default("cloud", DEFAULT_CLOUD)?
+            .set_default("oauth_version", DEFAULT_OAUTH_VERSION)?
+            .set_default("bind_address", DEFAULT_BIND_ADDRESS)?
             .set_default("port", DEFAULT_PORT)?
-            .add_source(File::with_name("config").required(false))
-            .add_source(Environment::with_prefix("AASM"))
+            .set_default("http_timeout_seconds", DEFAULT_HTTP_TIMEOUT_SECONDS)?
+            .set_default(
+                "http_connect_timeout_seconds",
+                DEFAULT_HTTP_CONNECT_TIMEOUT_SECONDS,
+            )?
+            .set_default("http_compression", true)?
+            .set_default("scrape_service_principals", false)?
+            .set_default("scrape_deleted_apps", false)?
+            .set_default("credential_name_label", true)?
+            .set_default("sign_in_audience_label", false)?
+            .set_default("max_label_length", DEFAULT_MAX_LABEL_LENGTH)?
+            .set_default("export_mode", DEFAULT_EXPORT_MODE)?
+            .set_default("permission_check", DEFAULT_PERMISSION_CHECK)?
+            .set_default("expiration_metric_name", DEFAULT_EXPIRATION_METRIC_NAME)?
+            .set_default("expiry_warning_days", DEFAULT_EXPIRY_WARNING_DAYS)?
+            .set_default("clock_offset_seconds", 0)?
+            .set_default("scrape_cache_ttl_seconds", 0)?
+            .set_default("fail_metrics_on_scrape_error", false)?
+            .set_default("max_concurrent_scrapes", DEFAULT_MAX_CONCURRENT_SCRAPES)?
+            .set_default("protect_status", false)?
+            .set_default(
+                "token_refresh_margin_seconds",
+                DEFAULT_TOKEN_REFRESH_MARGIN_SECONDS,
+            )?
+            .set_default("shutdown_timeout_seconds", DEFAULT_SHUTDOWN_TIMEOUT_SECONDS)?
+            .set_default("startup_grace_seconds", DEFAULT_STARTUP_GRACE_SECONDS)?
+            .set_default("log_format", DEFAULT_LOG_FORMAT)?
+            .set_default("home_page", DEFAULT_HOME_PAGE)?
+            .set_default("log_level", DEFAULT_LOG_LEVEL)?
+            // The later files override the earlier ones.
+            .add_source(config_files)
+            .add_source(
+                Environment::with_prefix("AASM")
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
+            .set_override_option("port", cli.port)?
+            .set_override_option("bind_address", cli.bind.clone())?
+            .set_override_option("log_format", cli.log_format.clone())?
+            .set_override_option("log_level", cli.log_level.clone())?
             .build()?;
 
         let config_port = config.get_int("port")?;
@@ -24,11 +363,349 @@ impl AppSettings {
             .try_into()
             .map_err(|_| anyhow!("Port out of range: {}", config_port))?;
 
+        let tenants = match get_optional::<String>(&config, "tenants")? {
+            Some(names) => names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    TenantSettings::fetch(&config, &format!("{}.", name.to_lowercase()))
+                        .with_context(|| format!("Invalid settings for tenant {}", name))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => vec![TenantSettings::fetch(&config, "")?],
+        };
+        if tenants.is_empty() {
+            return Err(anyhow!("tenants must name at least one tenant"));
+        }
+
+        let expiry_warning_days = config.get_int("expiry_warning_days")?;
+        if expiry_warning_days < 0 {
+            return Err(anyhow!(
+                "expiry_warning_days must not be negative: {}",
+                expiry_warning_days
+            ));
+        }
+
+        let graph_filter = get_optional::<String>(&config, "graph_filter")?;
+        if graph_filter
+            .as_deref()
+            .is_some_and(|filter| filter.trim().is_empty())
+        {
+            return Err(anyhow!("graph_filter must not be empty"));
+        }
+
+        let scrape_interval_seconds = get_optional::<u64>(&config, "scrape_interval_seconds")?;
+        if scrape_interval_seconds == Some(0) {
+            return Err(anyhow!("scrape_interval_seconds must not be 0"));
+        }
+
+        let max_label_length = config.get::<usize>("max_label_length")?;
+        if max_label_length == 0 {
+            return Err(anyhow!("max_label_length must not be 0"));
+        }
+
+        let token_max_consecutive_failures =
+            get_optional::<u32>(&config, "token_max_consecutive_failures")?;
+        if token_max_consecutive_failures == Some(0) {
+            return Err(anyhow!("token_max_consecutive_failures must not be 0"));
+        }
+
+        let max_concurrent_scrapes = config.get::<usize>("max_concurrent_scrapes")?;
+        if max_concurrent_scrapes == 0 {
+            return Err(anyhow!("max_concurrent_scrapes must not be 0"));
+        }
+
+        let page_size = get_optional::<u16>(&config, "page_size")?;
+        if page_size.is_some_and(|page_size| !(1..=MAX_PAGE_SIZE).contains(&page_size)) {
+            return Err(anyhow!("page_size must be between 1 and {}", MAX_PAGE_SIZE));
+        }
+
+        let exclude_app_name_regex = get_optional::<String>(&config, "exclude_app_name_regex")?
+            .map(|pattern| {
+                Regex::new(&pattern)
+                    .with_context(|| format!("Invalid exclude_app_name_regex: {}", pattern))
+            })
+            .transpose()?;
+
+        let include_app_ids = get_optional::<String>(&config, "include_app_ids")?
+            .map(|ids| {
+                ids.split(',')
+                    .map(str::trim)
+                    .filter(|id| !id.is_empty())
+                    .map(|id| {
+                        Uuid::parse_str(id)
+                            .with_context(|| format!("Invalid app id in include_app_ids: {}", id))
+                    })
+                    .collect::<Result<HashSet<_>>>()
+            })
+            .transpose()?
+            .filter(|ids| !ids.is_empty());
+
+        let scrape_timeout_seconds = get_optional::<u64>(&config, "scrape_timeout_seconds")?;
+        if scrape_timeout_seconds == Some(0) {
+            return Err(anyhow!("scrape_timeout_seconds must not be 0"));
+        }
+
+        let metrics_username = get_optional::<String>(&config, "metrics_username")?;
+        let metrics_password = get_optional_secret(&config, "metrics_password")?;
+        if metrics_username.is_some() != metrics_password.is_some() {
+            return Err(anyhow!(
+                "metrics_username and metrics_password must be set together"
+            ));
+        }
+
+        let proxy_client_cert_path = get_optional::<PathBuf>(&config, "proxy_client_cert_path")?;
+        let proxy_client_key_path = get_optional::<PathBuf>(&config, "proxy_client_key_path")?;
+        if proxy_client_cert_path.is_some() != proxy_client_key_path.is_some() {
+            return Err(anyhow!(
+                "proxy_client_cert_path and proxy_client_key_path must be set together"
+            ));
+        }
+
+        let tls_cert_path = get_optional::<PathBuf>(&config, "tls_cert_path")?;
+        let tls_key_path = get_optional::<PathBuf>(&config, "tls_key_path")?;
+        if tls_cert_path.is_some() != tls_key_path.is_some() {
+            return Err(anyhow!(
+                "tls_cert_path and tls_key_path must be set together"
+            ));
+        }
+
+        // The registry adds the underscore between the prefix and the metric name.
+        let metric_prefix = get_optional::<String>(&config, "metric_prefix")?
+            .map(|prefix| prefix.trim_end_matches('_').to_string())
+            .filter(|prefix| !prefix.is_empty());
+
+        // The registry appends the unit, which the name may already end with when copied from another exporter.
+        let expiration_metric_name = config.get_string("expiration_metric_name")?;
+        let expiration_metric_name = expiration_metric_name
+            .strip_suffix("_seconds")
+            .unwrap_or(&expiration_metric_name)
+            .to_string();
+        if !is_valid_metric_name(&expiration_metric_name) {
+            return Err(anyhow!(
+                "Invalid expiration_metric_name: {}",
+                expiration_metric_name
+            ));
+        }
+
+        let graph_url = get_optional::<String>(&config, "graph_url")?;
+        if graph_url.as_deref().is_some_and(str::is_empty) {
+            return Err(anyhow!("graph_url must not be empty"));
+        }
+
+        let listen_unix_socket = get_optional::<PathBuf>(&config, "listen_unix_socket")?;
+        if listen_unix_socket.is_some() && tls_cert_path.is_some() {
+            return Err(anyhow!(
+                "TLS isn't supported when listening on a Unix socket"
+            ));
+        }
+
         Ok(Self {
-            azure_client_id: config.get::<ClientId>("azure_client_id")?,
-            azure_client_secret: config.get::<ClientSecret>("azure_client_secret")?,
-            azure_tenant_id: config.get_string("azure_tenant_id")?,
+            tenants,
+            cloud: config.get_string("cloud")?.parse()?,
+            graph_url,
+            oauth_version: config.get_string("oauth_version")?.parse()?,
+            bind_address: parse_bind_address(&config.get_string("bind_address")?)?,
             port,
+            http_timeout_seconds: config.get::<u64>("http_timeout_seconds")?,
+            http_connect_timeout_seconds: config.get::<u64>("http_connect_timeout_seconds")?,
+            https_proxy: get_optional(&config, "https_proxy")?,
+            http_proxy: get_optional(&config, "http_proxy")?,
+            no_proxy: get_optional(&config, "no_proxy")?,
+            ca_certificate_path: get_optional(&config, "ca_certificate_path")?,
+            proxy_client_cert_path,
+            proxy_client_key_path,
+            http_compression: config.get_bool("http_compression")?,
+            scrape_service_principals: config.get_bool("scrape_service_principals")?,
+            scrape_deleted_apps: config.get_bool("scrape_deleted_apps")?,
+            graph_filter,
+            page_size,
+            exclude_app_name_regex,
+            include_app_ids,
+            credential_name_label: config.get_bool("credential_name_label")?,
+            export_mode: config.get_string("export_mode")?.parse()?,
+            expiry_warning_days,
+            clock_offset_seconds: config.get::<i64>("clock_offset_seconds")?,
+            tag_label_prefix: get_optional(&config, "tag_label_prefix")?,
+            sign_in_audience_label: config.get_bool("sign_in_audience_label")?,
+            max_label_length,
+            scrape_cache_ttl_seconds: config.get::<u64>("scrape_cache_ttl_seconds")?,
+            scrape_timeout_seconds,
+            scrape_interval_seconds,
+            state_file: get_optional(&config, "state_file")?,
+            max_concurrent_scrapes,
+            fail_metrics_on_scrape_error: config.get_bool("fail_metrics_on_scrape_error")?,
+            metrics_username,
+            metrics_password,
+            protect_status: config.get_bool("protect_status")?,
+            tls_cert_path,
+            tls_key_path,
+            listen_unix_socket,
+            shutdown_timeout_seconds: config.get::<u64>("shutdown_timeout_seconds")?,
+            startup_grace_seconds: config.get::<u64>("startup_grace_seconds")?,
+            token_refresh_margin_seconds: config.get::<u64>("token_refresh_margin_seconds")?,
+            token_max_consecutive_failures,
+            permission_check: config.get_string("permission_check")?.parse()?,
+            expiration_metric_name,
+            log_format: config.get_string("log_format")?.parse()?,
+            home_page: config.get_string("home_page")?.parse()?,
+            log_level: config.get_string("log_level")?,
+            otel_endpoint: get_optional(&config, "otel_endpoint")?,
+            metric_prefix,
         })
     }
 }
+
+/// The configuration files at `path`, which is either a file, or a directory whose files are taken in alphabetical
+/// order, ignoring the hidden ones.
+fn config_file_paths(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut paths = vec![];
+    let entries = std::fs::read_dir(path)
+        .with_context(|| format!("Failed to read configuration directory {}", path.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| {
+            format!("Failed to read configuration directory {}", path.display())
+        })?;
+        let entry_path = entry.path();
+        if entry_path.is_file() && !entry.file_name().to_string_lossy().starts_with('.') {
+            paths.push(entry_path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Parse an IPv4 or IPv6 address, the latter possibly between brackets as in URLs, such as `[::]`.
+fn parse_bind_address(address: &str) -> Result<IpAddr> {
+    let trimmed = address
+        .strip_prefix('[')
+        .and_then(|address| address.strip_suffix(']'))
+        .unwrap_or(address);
+    trimmed
+        .parse()
+        .with_context(|| format!("Invalid bind address: {}", address))
+}
+
+/// Whether the name is a valid Prometheus metric name, which the OpenMetrics output doesn't escape.
+fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Retrieve a setting which may be absent, while still failing on invalid values.
+fn get_optional<'de, T: Deserialize<'de>>(config: &Config, key: &str) -> Result<Option<T>> {
+    match config.get::<T>(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Check that the tenant is either a GUID or a domain name, so that typos are caught before requesting a token.
+fn validate_tenant_id(tenant_id: String) -> Result<String> {
+    let is_guid = Uuid::parse_str(&tenant_id).is_ok();
+    let is_domain = tenant_id.contains('.')
+        && !tenant_id.starts_with('.')
+        && !tenant_id.ends_with('.')
+        && tenant_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+
+    if is_guid || is_domain {
+        Ok(tenant_id)
+    } else {
+        Err(anyhow!(
+            "Invalid tenant id, expected a GUID or a domain name: {}",
+            tenant_id
+        ))
+    }
+}
+
+/// Read an optional secret setting, resolving it when it's a reference.
+fn get_optional_secret(config: &Config, key: &str) -> Result<Option<String>> {
+    get_optional::<String>(config, key)?
+        .map(|value| resolve_secret(value).with_context(|| format!("Failed to resolve {}", key)))
+        .transpose()
+}
+
+/// Resolve a secret given as a reference to where it's actually stored, such as `file:/run/secrets/contoso`.
+///
+/// Values without a known scheme are taken as is. Other secret stores can be supported by adding their scheme here.
+fn resolve_secret(value: String) -> Result<String> {
+    match value.split_once(':') {
+        Some(("file", path)) => read_secret_file(Path::new(path)),
+        Some(("env", name)) => std::env::var(name)
+            .with_context(|| format!("Failed to read secret environment variable {}", name)),
+        _ => Ok(value),
+    }
+}
+
+/// Read a secret from a file, such as a Docker or Kubernetes secret, ignoring the trailing newline.
+fn read_secret_file(path: &Path) -> Result<String> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read secret file {}", path.display()))?;
+    Ok(content.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+#[cfg(test)]
+impl AppSettings {
+    /// Load the settings from `toml`, as if it were the only configuration file.
+    pub fn from_toml(toml: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("aasm-test-{}.toml", Uuid::new_v4()));
+        std::fs::write(&path, toml).unwrap();
+        let cli = Cli {
+            config: vec![path.clone()],
+            port: None,
+            bind: None,
+            log_format: None,
+            log_level: None,
+            once: false,
+        };
+        let settings = Self::fetch(&cli);
+        std::fs::remove_file(&path).unwrap();
+        settings.unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn bind_address_ipv4() {
+        assert_eq!(
+            parse_bind_address("0.0.0.0").unwrap(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        );
+    }
+
+    #[test]
+    fn bind_address_ipv6() {
+        assert_eq!(
+            parse_bind_address("::1").unwrap(),
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+    }
+
+    #[test]
+    fn bind_address_bracketed_ipv6() {
+        assert_eq!(
+            parse_bind_address("[::]").unwrap(),
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        );
+    }
+
+    #[test]
+    fn bind_address_invalid() {
+        assert!(parse_bind_address("[0.0.0.0").is_err());
+        assert!(parse_bind_address("localhost").is_err());
+    }
+}